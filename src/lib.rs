@@ -1,13 +1,68 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Display;
 use std::fs;
-use std::path::Path;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::result::Result;
+use std::str::FromStr;
 
 const INVALID_SIGNATURE: &str = "Invalid signature!";
 const INVALID_FORMAT: &str = "Invalid file format!";
 const UNEXPECTED_EOF: &str = "Unexpected end of file!";
+const NO_FRAMES: &str = "No frames given!";
+const BINARY_FORMAT: &str = "Binary formats can't be parsed from a string!";
 
-#[derive(Debug, Clone)]
+const PHOTO_MIN_UNIQUE_RATIO: f64 = 0.01;
+const PHOTO_MAX_HISTOGRAM_ROUGHNESS: f64 = 0.5;
+const PHOTO_HISTOGRAM_BINS: usize = 32;
+
+const GLYPH_WIDTH: usize = 5;
+const GLYPH_HEIGHT: usize = 7;
+const GLYPH_ADVANCE: usize = GLYPH_WIDTH + 1;
+
+#[rustfmt::skip]
+const FONT: [u8; 95 * GLYPH_WIDTH] = [
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x5F, 0x00, 0x00, 0x00, 0x07, 0x00, 0x07, 0x00,
+    0x14, 0x7F, 0x14, 0x7F, 0x14, 0x24, 0x2A, 0x7F, 0x2A, 0x12, 0x23, 0x13, 0x08, 0x64, 0x62,
+    0x36, 0x49, 0x55, 0x22, 0x50, 0x00, 0x05, 0x03, 0x00, 0x00, 0x00, 0x1C, 0x22, 0x41, 0x00,
+    0x00, 0x41, 0x22, 0x1C, 0x00, 0x08, 0x2A, 0x1C, 0x2A, 0x08, 0x08, 0x08, 0x3E, 0x08, 0x08,
+    0x00, 0x50, 0x30, 0x00, 0x00, 0x08, 0x08, 0x08, 0x08, 0x08, 0x00, 0x60, 0x60, 0x00, 0x00,
+    0x20, 0x10, 0x08, 0x04, 0x02, 0x3E, 0x51, 0x49, 0x45, 0x3E, 0x00, 0x42, 0x7F, 0x40, 0x00,
+    0x42, 0x61, 0x51, 0x49, 0x46, 0x21, 0x41, 0x45, 0x4B, 0x31, 0x18, 0x14, 0x12, 0x7F, 0x10,
+    0x27, 0x45, 0x45, 0x45, 0x39, 0x3C, 0x4A, 0x49, 0x49, 0x30, 0x01, 0x71, 0x09, 0x05, 0x03,
+    0x36, 0x49, 0x49, 0x49, 0x36, 0x06, 0x49, 0x49, 0x29, 0x1E, 0x00, 0x36, 0x36, 0x00, 0x00,
+    0x00, 0x56, 0x36, 0x00, 0x00, 0x08, 0x14, 0x22, 0x41, 0x00, 0x14, 0x14, 0x14, 0x14, 0x14,
+    0x00, 0x41, 0x22, 0x14, 0x08, 0x02, 0x01, 0x51, 0x09, 0x06, 0x32, 0x49, 0x79, 0x41, 0x3E,
+    0x7E, 0x11, 0x11, 0x11, 0x7E, 0x7F, 0x49, 0x49, 0x49, 0x36, 0x3E, 0x41, 0x41, 0x41, 0x22,
+    0x7F, 0x41, 0x41, 0x22, 0x1C, 0x7F, 0x49, 0x49, 0x49, 0x41, 0x7F, 0x09, 0x09, 0x01, 0x01,
+    0x3E, 0x41, 0x41, 0x51, 0x32, 0x7F, 0x08, 0x08, 0x08, 0x7F, 0x00, 0x41, 0x7F, 0x41, 0x00,
+    0x20, 0x40, 0x41, 0x3F, 0x01, 0x7F, 0x08, 0x14, 0x22, 0x41, 0x7F, 0x40, 0x40, 0x40, 0x40,
+    0x7F, 0x02, 0x04, 0x02, 0x7F, 0x7F, 0x04, 0x08, 0x10, 0x7F, 0x3E, 0x41, 0x41, 0x41, 0x3E,
+    0x7F, 0x09, 0x09, 0x09, 0x06, 0x3E, 0x41, 0x51, 0x21, 0x5E, 0x7F, 0x09, 0x19, 0x29, 0x46,
+    0x46, 0x49, 0x49, 0x49, 0x31, 0x01, 0x01, 0x7F, 0x01, 0x01, 0x3F, 0x40, 0x40, 0x40, 0x3F,
+    0x1F, 0x20, 0x40, 0x20, 0x1F, 0x7F, 0x20, 0x18, 0x20, 0x7F, 0x63, 0x14, 0x08, 0x14, 0x63,
+    0x03, 0x04, 0x78, 0x04, 0x03, 0x61, 0x51, 0x49, 0x45, 0x43, 0x00, 0x7F, 0x41, 0x41, 0x00,
+    0x02, 0x04, 0x08, 0x10, 0x20, 0x00, 0x41, 0x41, 0x7F, 0x00, 0x04, 0x02, 0x01, 0x02, 0x04,
+    0x40, 0x40, 0x40, 0x40, 0x40, 0x00, 0x01, 0x02, 0x04, 0x00, 0x20, 0x54, 0x54, 0x54, 0x78,
+    0x7F, 0x48, 0x44, 0x44, 0x38, 0x38, 0x44, 0x44, 0x44, 0x20, 0x38, 0x44, 0x44, 0x48, 0x7F,
+    0x38, 0x54, 0x54, 0x54, 0x18, 0x08, 0x7E, 0x09, 0x01, 0x02, 0x08, 0x14, 0x54, 0x54, 0x3C,
+    0x7F, 0x08, 0x04, 0x04, 0x78, 0x00, 0x44, 0x7D, 0x40, 0x00, 0x20, 0x40, 0x44, 0x3D, 0x00,
+    0x00, 0x7F, 0x10, 0x28, 0x44, 0x00, 0x41, 0x7F, 0x40, 0x00, 0x7C, 0x04, 0x18, 0x04, 0x78,
+    0x7C, 0x08, 0x04, 0x04, 0x78, 0x38, 0x44, 0x44, 0x44, 0x38, 0x7C, 0x14, 0x14, 0x14, 0x08,
+    0x08, 0x14, 0x14, 0x18, 0x7C, 0x7C, 0x08, 0x04, 0x04, 0x08, 0x48, 0x54, 0x54, 0x54, 0x20,
+    0x04, 0x3F, 0x44, 0x40, 0x20, 0x3C, 0x40, 0x40, 0x20, 0x7C, 0x1C, 0x20, 0x40, 0x20, 0x1C,
+    0x3C, 0x40, 0x30, 0x40, 0x3C, 0x44, 0x28, 0x10, 0x28, 0x44, 0x0C, 0x50, 0x50, 0x50, 0x3C,
+    0x44, 0x64, 0x54, 0x4C, 0x44, 0x00, 0x08, 0x36, 0x41, 0x00, 0x00, 0x00, 0x7F, 0x00, 0x00,
+    0x00, 0x41, 0x36, 0x08, 0x00, 0x10, 0x08, 0x08, 0x10, 0x08,
+];
+
+const HUE_MIN_SATURATION: f32 = 0.1;
+
+const ORIENTATION_MAX_DIFFERENCE: f64 = 8.0;
+
+const TOLERANT_DIFF_TOLERANCE: u8 = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Color {
     red: u8,
     green: u8,
@@ -30,6 +85,132 @@ impl Color {
     pub fn blue(&self) -> u8 {
         self.blue
     }
+
+    pub fn luma(&self) -> u8 {
+        (0.299 * self.red as f32 + 0.587 * self.green as f32 + 0.114 * self.blue as f32).round()
+            as u8
+    }
+
+    pub fn to_hsv(&self) -> (f32, f32, f32) {
+        let red = self.red as f32 / 255.0;
+        let green = self.green as f32 / 255.0;
+        let blue = self.blue as f32 / 255.0;
+        let max = red.max(green).max(blue);
+        let min = red.min(green).min(blue);
+        let delta = max - min;
+        let hue = if delta == 0.0 {
+            0.0
+        } else if max == red {
+            60.0 * ((green - blue) / delta).rem_euclid(6.0)
+        } else if max == green {
+            60.0 * ((blue - red) / delta + 2.0)
+        } else {
+            60.0 * ((red - green) / delta + 4.0)
+        };
+        let saturation = if max == 0.0 { 0.0 } else { delta / max };
+        (hue, saturation, max)
+    }
+
+    pub fn from_hsv(hue: f32, saturation: f32, value: f32) -> Self {
+        let hue = hue.rem_euclid(360.0);
+        let chroma = value * saturation;
+        let x = chroma * (1.0 - ((hue / 60.0).rem_euclid(2.0) - 1.0).abs());
+        let m = value - chroma;
+        let (red, green, blue) = match (hue / 60.0) as u32 {
+            0 => (chroma, x, 0.0),
+            1 => (x, chroma, 0.0),
+            2 => (0.0, chroma, x),
+            3 => (0.0, x, chroma),
+            4 => (x, 0.0, chroma),
+            _ => (chroma, 0.0, x),
+        };
+        Self::new(
+            ((red + m) * 255.0).round() as u8,
+            ((green + m) * 255.0).round() as u8,
+            ((blue + m) * 255.0).round() as u8,
+        )
+    }
+
+    /// CIELAB coordinates (D65 white point) of the sRGB color.
+    pub fn to_lab(&self) -> (f64, f64, f64) {
+        let red = srgb_to_linear(self.red) as f64;
+        let green = srgb_to_linear(self.green) as f64;
+        let blue = srgb_to_linear(self.blue) as f64;
+        let x = (0.4124564 * red + 0.3575761 * green + 0.1804375 * blue) / 0.95047;
+        let y = 0.2126729 * red + 0.7151522 * green + 0.0721750 * blue;
+        let z = (0.0193339 * red + 0.119192 * green + 0.9503041 * blue) / 1.08883;
+        let f = |t: f64| {
+            if t > 216.0 / 24389.0 {
+                t.cbrt()
+            } else {
+                (24389.0 / 27.0 * t + 16.0) / 116.0
+            }
+        };
+        let (fx, fy, fz) = (f(x), f(y), f(z));
+        (116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz))
+    }
+
+    /// CIEDE2000 color difference.
+    pub fn delta_e(&self, other: &Color) -> f64 {
+        let (l1, a1, b1) = self.to_lab();
+        let (l2, a2, b2) = other.to_lab();
+        let c_bar = (a1.hypot(b1) + a2.hypot(b2)) / 2.0;
+        let g = 0.5 * (1.0 - (c_bar.powi(7) / (c_bar.powi(7) + 25f64.powi(7))).sqrt());
+        let (a1, a2) = (a1 * (1.0 + g), a2 * (1.0 + g));
+        let (c1, c2) = (a1.hypot(b1), a2.hypot(b2));
+        let hue = |b: f64, a: f64| {
+            if a == 0.0 && b == 0.0 {
+                0.0
+            } else {
+                b.atan2(a).to_degrees().rem_euclid(360.0)
+            }
+        };
+        let (h1, h2) = (hue(b1, a1), hue(b2, a2));
+        let delta_l = l2 - l1;
+        let delta_c = c2 - c1;
+        let delta_h = if c1 * c2 == 0.0 {
+            0.0
+        } else if (h2 - h1).abs() <= 180.0 {
+            h2 - h1
+        } else if h2 <= h1 {
+            h2 - h1 + 360.0
+        } else {
+            h2 - h1 - 360.0
+        };
+        let delta_h = 2.0 * (c1 * c2).sqrt() * (delta_h / 2.0).to_radians().sin();
+        let l_bar = (l1 + l2) / 2.0;
+        let c_bar = (c1 + c2) / 2.0;
+        let h_bar = if c1 * c2 == 0.0 {
+            h1 + h2
+        } else if (h1 - h2).abs() <= 180.0 {
+            (h1 + h2) / 2.0
+        } else if h1 + h2 < 360.0 {
+            (h1 + h2 + 360.0) / 2.0
+        } else {
+            (h1 + h2 - 360.0) / 2.0
+        };
+        let t = 1.0 - 0.17 * (h_bar - 30.0).to_radians().cos()
+            + 0.24 * (2.0 * h_bar).to_radians().cos()
+            + 0.32 * (3.0 * h_bar + 6.0).to_radians().cos()
+            - 0.20 * (4.0 * h_bar - 63.0).to_radians().cos();
+        let delta_theta = 30.0 * (-((h_bar - 275.0) / 25.0).powi(2)).exp();
+        let r_c = 2.0 * (c_bar.powi(7) / (c_bar.powi(7) + 25f64.powi(7))).sqrt();
+        let s_l = 1.0 + 0.015 * (l_bar - 50.0).powi(2) / (20.0 + (l_bar - 50.0).powi(2)).sqrt();
+        let s_c = 1.0 + 0.045 * c_bar;
+        let s_h = 1.0 + 0.015 * c_bar * t;
+        let r_t = -(2.0 * delta_theta).to_radians().sin() * r_c;
+        ((delta_l / s_l).powi(2)
+            + (delta_c / s_c).powi(2)
+            + (delta_h / s_h).powi(2)
+            + r_t * (delta_c / s_c) * (delta_h / s_h))
+            .sqrt()
+    }
+
+    fn is_close(&self, other: &Color, tolerance: u8) -> bool {
+        self.red.abs_diff(other.red) <= tolerance
+            && self.green.abs_diff(other.green) <= tolerance
+            && self.blue.abs_diff(other.blue) <= tolerance
+    }
 }
 
 impl Display for Color {
@@ -109,6 +290,15 @@ impl Ppm {
             .map(|(i, pixel)| (i % self.width, i / self.width, pixel))
     }
 
+    pub fn iter_serpentine(&self) -> impl Iterator<Item = (usize, usize, &Color)> {
+        (0..self.height).flat_map(move |y| {
+            (0..self.width).map(move |i| {
+                let x = if y % 2 == 0 { i } else { self.width - 1 - i };
+                (x, y, &self.pixels[y * self.width + x])
+            })
+        })
+    }
+
     pub fn set_pixels(&mut self, pixels: &[Color]) {
         self.pixels = pixels.to_vec();
     }
@@ -130,70 +320,206 @@ impl Ppm {
         }
     }
 
+    /// Guesses whether the image is a photo (many colors, smooth tones) or a
+    /// graphic (few colors, hard edges).
+    ///
+    /// The image counts as photographic when at least 1% of its pixels have a
+    /// distinct color and the luma histogram, folded into 32 bins, has a
+    /// roughness below 0.5. Roughness is the summed absolute difference
+    /// between neighbouring bins divided by twice the pixel count, so a
+    /// histogram made of isolated spikes scores 1.0 and a flat one close to 0.
+    pub fn is_photographic(&self) -> bool {
+        if self.pixels.is_empty() {
+            return false;
+        }
+        let total = self.pixels.len() as f64;
+        let unique = self.pixels.iter().collect::<HashSet<_>>().len() as f64;
+        let mut histogram = [0usize; PHOTO_HISTOGRAM_BINS];
+        for pixel in &self.pixels {
+            histogram[pixel.luma() as usize * PHOTO_HISTOGRAM_BINS / 256] += 1;
+        }
+        let mut roughness = 0;
+        let mut previous = 0;
+        for count in histogram.iter().chain(&[0]) {
+            roughness += count.abs_diff(previous);
+            previous = *count;
+        }
+        let roughness = roughness as f64 / (2.0 * total);
+        unique / total >= PHOTO_MIN_UNIQUE_RATIO && roughness < PHOTO_MAX_HISTOGRAM_ROUGHNESS
+    }
+
     pub fn from_file(path: impl AsRef<Path>) -> Result<Self, Error> {
         let bytes = fs::read(path)?;
         Self::from_bytes(&bytes)
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
-        let mut size = (None, None);
-        let mut color_depth = None;
-        let mut i = 0;
-        let mut next = 0;
-        while let Some(pos) = bytes[i..].iter().position(|b| 0xA.eq(b)) {
-            let chunk = &bytes[i..i + pos];
-            i += pos + 1;
-            if chunk.starts_with(&[0x23]) {
-                continue;
-            }
-            match next {
-                0 => {
-                    if ![0x50, 0x36].eq(chunk) {
-                        return Err(INVALID_SIGNATURE.into());
-                    }
-                    next += 1;
-                }
-                1 => {
-                    let dimensions = String::from_utf8_lossy(chunk);
-                    let (width, height) =
-                        dimensions.split_once(0x20 as char).ok_or(UNEXPECTED_EOF)?;
-                    let width = width.parse::<usize>().map_err(|_| INVALID_FORMAT)?;
-                    let height = height.parse::<usize>().map_err(|_| INVALID_FORMAT)?;
-                    size = (Some(width), Some(height));
-                    next += 1;
-                }
-                2 => {
-                    color_depth = Some(
-                        String::from_utf8_lossy(chunk)
-                            .parse::<usize>()
-                            .map_err(|_| INVALID_FORMAT)?,
-                    );
-                    break;
+    pub fn to_file(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn write_frames(
+        frames: &[Ppm],
+        dir: impl AsRef<Path>,
+        prefix: &str,
+    ) -> Result<Vec<PathBuf>, Error> {
+        let digits = frames.len().saturating_sub(1).to_string().len().max(5);
+        let mut paths = Vec::with_capacity(frames.len());
+        for (i, frame) in frames.iter().enumerate() {
+            let path = dir
+                .as_ref()
+                .join(format!("{}_{:0width$}.ppm", prefix, i, width = digits));
+            frame.to_file(&path)?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+
+    pub fn write_multi<W: Write>(frames: &[Ppm], w: &mut W) -> Result<(), Error> {
+        for frame in frames {
+            w.write_all(&frame.to_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Splits the image into `tile_size` squares named `tile_{col}_{row}.ppm`.
+    /// Tiles on the right and bottom edges are padded with black.
+    pub fn write_tiles(
+        &self,
+        tile_size: usize,
+        dir: impl AsRef<Path>,
+    ) -> Result<Vec<PathBuf>, Error> {
+        if tile_size == 0 {
+            return Err("Tile size must be positive!".into());
+        }
+        let mut paths = Vec::new();
+        for row in 0..self.height.div_ceil(tile_size) {
+            for col in 0..self.width.div_ceil(tile_size) {
+                let mut tile = Ppm::new(tile_size, tile_size);
+                tile.color_depth = self.color_depth;
+                for y in 0..tile_size.min(self.height - row * tile_size) {
+                    let start = (row * tile_size + y) * self.width + col * tile_size;
+                    let len = tile_size.min(self.width - col * tile_size);
+                    tile.pixels[y * tile_size..y * tile_size + len]
+                        .clone_from_slice(&self.pixels[start..start + len]);
                 }
-                _ => unreachable!(),
+                let path = dir.as_ref().join(format!("tile_{}_{}.ppm", col, row));
+                tile.to_file(&path)?;
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        Self::from_bytes_with_order(bytes, PixelOrder::RowMajor)
+    }
+
+    /// Decodes back-to-back images until `bytes` runs out. Whitespace between
+    /// images is skipped, and a truncated image is an error.
+    pub fn from_bytes_multi(bytes: &[u8]) -> Result<Vec<Ppm>, Error> {
+        let mut frames = Vec::new();
+        let mut rest = bytes;
+        loop {
+            let start = rest.iter().position(|b| !b.is_ascii_whitespace());
+            let Some(start) = start else {
+                return Ok(frames);
+            };
+            rest = &rest[start..];
+            let header = parse_header(rest)?;
+            let end = header
+                .width
+                .saturating_mul(header.height)
+                .saturating_mul(3)
+                .saturating_add(header.offset);
+            if rest.len() < end {
+                return Err(UNEXPECTED_EOF.into());
             }
+            frames.push(Self::from_bytes(&rest[..end])?);
+            rest = &rest[end..];
+        }
+    }
+
+    pub fn from_bytes_limited(bytes: &[u8], max_pixels: usize) -> Result<Self, Error> {
+        let Header { width, height, .. } = parse_header(bytes)?;
+        match width.checked_mul(height) {
+            Some(pixels) if pixels <= max_pixels => Self::from_bytes(bytes),
+            _ => Err(format!(
+                "Image size ({}, {}) exceeds the limit of {} pixels!",
+                width, height, max_pixels
+            )
+            .into()),
         }
-        if let ((Some(width), Some(height)), Some(color_depth)) = (size, color_depth) {
-            let data = &bytes[i..];
-            let mut ppm = Ppm::new(width, height);
-            ppm.color_depth = color_depth;
-            let mut y = 0;
-            let mut x = 0;
-            for i in 0..data.len() / 3 {
-                if i > 0 && i % width == 0 {
-                    y += 1;
-                    x = 0;
+    }
+
+    /// Decodes at most `max_rows` rows, stopping early at the last complete
+    /// row in `bytes`. The returned height is the number of rows decoded.
+    pub fn from_bytes_partial(bytes: &[u8], max_rows: usize) -> Result<Self, Error> {
+        let header = parse_header(bytes)?;
+        let data = &bytes[header.offset..];
+        let row_len = header.width.checked_mul(3).ok_or(INVALID_FORMAT)?;
+        let rows = match row_len {
+            0 => header.height,
+            _ => data.len() / row_len,
+        }
+        .min(header.height)
+        .min(max_rows);
+        let mut ppm = Ppm::new(header.width, rows);
+        ppm.color_depth = header.color_depth;
+        for (pixel, sample) in ppm.pixels.iter_mut().zip(data.chunks_exact(3)) {
+            *pixel = Color::new(sample[0], sample[1], sample[2]);
+        }
+        Ok(ppm)
+    }
+
+    pub fn from_bytes_with_order(bytes: &[u8], order: PixelOrder) -> Result<Self, Error> {
+        let header = parse_header(bytes)?;
+        let (width, height) = (header.width, header.height);
+        let data = &bytes[header.offset..];
+        let mut ppm = Ppm::new(width, height);
+        ppm.color_depth = header.color_depth;
+        for i in 0..(data.len() / 3).min(width * height) {
+            let (x, y) = match order {
+                PixelOrder::RowMajor => (i % width, i / width),
+                PixelOrder::ColumnMajor => (i / height, i % height),
+            };
+            let red = data[i * 3];
+            let green = data[i * 3 + 1];
+            let blue = data[i * 3 + 2];
+            ppm.pixels[y * width + x] = Color::new(red, green, blue);
+        }
+        Ok(ppm)
+    }
+
+    pub fn from_byte_iter<I: Iterator<Item = u8>>(mut iter: I) -> Result<Ppm, Error> {
+        let mut header = Vec::new();
+        let mut lines = 0;
+        while lines < 3 {
+            let Some(byte) = iter.next() else {
+                break;
+            };
+            header.push(byte);
+            if byte == 0xA {
+                let start = header[..header.len() - 1]
+                    .iter()
+                    .rposition(|b| 0xA.eq(b))
+                    .map_or(0, |pos| pos + 1);
+                if !header[start..].starts_with(&[0x23]) {
+                    lines += 1;
                 }
-                let red = data[i * 3];
-                let green = data[i * 3 + 1];
-                let blue = data[i * 3 + 2];
-                ppm.pixels[y * width + x] = Color::new(red, green, blue);
-                x += 1;
             }
-            Ok(ppm)
-        } else {
-            Err(INVALID_FORMAT.into())
         }
+        let header = parse_header(&header)?;
+        let mut ppm = Ppm::new(header.width, header.height);
+        ppm.color_depth = header.color_depth;
+        for pixel in ppm.pixels.iter_mut() {
+            let (Some(red), Some(green), Some(blue)) = (iter.next(), iter.next(), iter.next())
+            else {
+                break;
+            };
+            *pixel = Color::new(red, green, blue);
+        }
+        Ok(ppm)
     }
 
     pub fn to_bytes(&self) -> Vec<u8> {
@@ -211,37 +537,4085 @@ impl Ppm {
         }
         bytes
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct Error {
-    message: String,
-}
+    /// Writes P6 with each entry as a `# key: value` header comment, which
+    /// other readers ignore. Fails if a key contains `": "` or if a key or
+    /// value contains a newline.
+    pub fn to_bytes_with_metadata(
+        &self,
+        meta: &BTreeMap<String, String>,
+    ) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&[0x50, 0x36, 0xA]);
+        for (key, value) in meta {
+            if key.contains(": ") || key.contains('\n') || value.contains('\n') {
+                return Err(format!("Invalid metadata entry {:?}!", key).into());
+            }
+            bytes.extend_from_slice(format!("# {}: {}", key, value).as_bytes());
+            bytes.extend_from_slice(&[0xA]);
+        }
+        bytes.extend(self.to_bytes().into_iter().skip(3));
+        Ok(bytes)
+    }
 
-impl Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}", self.message)
+    pub fn from_bytes_with_metadata(
+        bytes: &[u8],
+    ) -> Result<(Ppm, BTreeMap<String, String>), Error> {
+        let ppm = Ppm::from_bytes(bytes)?;
+        let header = parse_header(bytes)?;
+        let meta = bytes[..header.offset]
+            .split(|&b| b == 0xA)
+            .filter_map(|line| line.strip_prefix(b"# "))
+            .filter_map(|line| {
+                let line = String::from_utf8_lossy(line);
+                let (key, value) = line.split_once(": ")?;
+                Some((key.to_string(), value.to_string()))
+            })
+            .collect();
+        Ok((ppm, meta))
     }
-}
 
-impl From<&str> for Error {
-    fn from(err: &str) -> Self {
-        Self {
-            message: err.to_string(),
+    /// Encodes as an uncompressed 24-bit BMP, which unlike PPM is understood
+    /// by browsers.
+    pub fn to_bmp(&self) -> Vec<u8> {
+        let row_len = (self.width * 3).div_ceil(4) * 4;
+        let data_len = row_len * self.height;
+        let file_len = 54 + data_len;
+        let mut bytes = Vec::with_capacity(file_len);
+        bytes.extend_from_slice(b"BM");
+        bytes.extend_from_slice(&(file_len as u32).to_le_bytes());
+        bytes.extend_from_slice(&[0; 4]);
+        bytes.extend_from_slice(&54u32.to_le_bytes());
+        bytes.extend_from_slice(&40u32.to_le_bytes());
+        bytes.extend_from_slice(&(self.width as i32).to_le_bytes());
+        bytes.extend_from_slice(&(self.height as i32).to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&24u16.to_le_bytes());
+        bytes.extend_from_slice(&0u32.to_le_bytes());
+        bytes.extend_from_slice(&(data_len as u32).to_le_bytes());
+        // 2835 pixels per meter is 72 DPI.
+        bytes.extend_from_slice(&2835u32.to_le_bytes());
+        bytes.extend_from_slice(&2835u32.to_le_bytes());
+        bytes.extend_from_slice(&[0; 8]);
+        for y in (0..self.height).rev() {
+            for pixel in &self.pixels[y * self.width..(y + 1) * self.width] {
+                bytes.extend_from_slice(&[pixel.blue, pixel.green, pixel.red]);
+            }
+            bytes.resize(bytes.len() + row_len - self.width * 3, 0);
         }
+        bytes
     }
-}
 
-impl From<String> for Error {
-    fn from(err: String) -> Self {
-        Self { message: err }
+    /// An SVG document showing the image, embedded as a base64 BMP data URI.
+    pub fn to_svg(&self) -> String {
+        format!(
+            concat!(
+                "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" ",
+                "viewBox=\"0 0 {0} {1}\">",
+                "<image width=\"{0}\" height=\"{1}\" href=\"data:image/bmp;base64,{2}\"/>",
+                "</svg>"
+            ),
+            self.width,
+            self.height,
+            base64(&self.to_bmp())
+        )
     }
-}
 
-impl From<std::io::Error> for Error {
-    fn from(err: std::io::Error) -> Self {
-        Self {
-            message: err.to_string(),
+    pub fn to_bytes_pgm(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&[0x50, 0x35, 0xA]);
+        bytes.extend_from_slice(format!("{} {}", self.width, self.height).as_bytes());
+        bytes.extend_from_slice(&[0xA]);
+        bytes.extend_from_slice(self.color_depth.to_string().as_bytes());
+        bytes.extend_from_slice(&[0xA]);
+        bytes.extend(self.pixels.iter().map(Color::luma));
+        bytes
+    }
+
+    pub fn is_grayscale(&self) -> bool {
+        self.pixels
+            .iter()
+            .all(|pixel| pixel.red == pixel.green && pixel.green == pixel.blue)
+    }
+
+    /// Writes grayscale images as P5 and everything else as P6, returning the
+    /// format that was used.
+    pub fn save_optimal(&self, path: impl AsRef<Path>) -> Result<PnmFormat, Error> {
+        if self.is_grayscale() {
+            fs::write(path, self.to_bytes_pgm())?;
+            Ok(PnmFormat::P5)
+        } else {
+            self.to_file(path)?;
+            Ok(PnmFormat::P6)
+        }
+    }
+
+    /// Rotates the hue of every pixel within `range` degrees of `center_hue`
+    /// by `shift` degrees. Pixels with a saturation below 0.1 are left alone
+    /// since their hue carries no meaning.
+    pub fn replace_hue_range(&self, center_hue: f32, range: f32, shift: f32) -> Ppm {
+        let mut ppm = self.clone();
+        for pixel in ppm.pixels.iter_mut() {
+            let (hue, saturation, value) = pixel.to_hsv();
+            if saturation < HUE_MIN_SATURATION {
+                continue;
+            }
+            let distance = (hue - center_hue).rem_euclid(360.0);
+            if distance.min(360.0 - distance) <= range {
+                *pixel = Color::from_hsv(hue + shift, saturation, value);
+            }
+        }
+        ppm
+    }
+
+    /// Intersection over union of the two masks, where pixels with a luma
+    /// above `threshold` are foreground. Two empty masks count as identical.
+    pub fn mask_iou(&self, other: &Ppm, threshold: u8) -> Result<f64, Error> {
+        self.check_same_size(other)?;
+        let mut intersection = 0usize;
+        let mut union = 0usize;
+        for (a, b) in self.pixels.iter().zip(&other.pixels) {
+            let a = a.luma() > threshold;
+            let b = b.luma() > threshold;
+            if a && b {
+                intersection += 1;
+            }
+            if a || b {
+                union += 1;
+            }
+        }
+        if union == 0 {
+            return Ok(1.0);
+        }
+        Ok(intersection as f64 / union as f64)
+    }
+
+    fn check_same_size(&self, other: &Ppm) -> Result<(), Error> {
+        if self.width != other.width || self.height != other.height {
+            return Err(format!(
+                "Image size ({}, {}) doesn't match image size ({}, {})!",
+                self.width, self.height, other.width, other.height
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    pub fn rotate(&self, degrees: f32, background: Color) -> Ppm {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let cx = (self.width as f32 - 1.0) / 2.0;
+        let cy = (self.height as f32 - 1.0) / 2.0;
+        let mut ppm = self.clone();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let dx = x as f32 - cx;
+                let dy = y as f32 - cy;
+                let sx = dx * cos + dy * sin + cx;
+                let sy = -dx * sin + dy * cos + cy;
+                ppm.pixels[y * self.width + x] = if self.contains(sx, sy) {
+                    self.sample_bilinear(sx, sy)
+                } else {
+                    background.clone()
+                };
+            }
+        }
+        ppm
+    }
+
+    /// Rotates the image clockwise about its center and crops it to the
+    /// largest axis-aligned rectangle that only contains original content.
+    pub fn rotate_crop(&self, degrees: f32) -> Ppm {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let (width, height) = inscribed_size(self.width as f32, self.height as f32, sin, cos);
+        let width = (width + 1e-3).floor() as usize;
+        let height = (height + 1e-3).floor() as usize;
+        let cx = (self.width as f32 - 1.0) / 2.0;
+        let cy = (self.height as f32 - 1.0) / 2.0;
+        let mut ppm = Ppm::new(width, height);
+        ppm.color_depth = self.color_depth;
+        for y in 0..height {
+            for x in 0..width {
+                let dx = x as f32 - (width as f32 - 1.0) / 2.0;
+                let dy = y as f32 - (height as f32 - 1.0) / 2.0;
+                let sx = dx * cos + dy * sin + cx;
+                let sy = -dx * sin + dy * cos + cy;
+                ppm.pixels[y * width + x] = self.sample_bilinear(sx, sy);
+            }
+        }
+        ppm
+    }
+
+    /// Tolerates float error so that right-angle rotations keep their corners.
+    fn contains(&self, x: f32, y: f32) -> bool {
+        const EPSILON: f32 = 1e-3;
+        x >= -EPSILON
+            && y >= -EPSILON
+            && x <= self.width as f32 - 1.0 + EPSILON
+            && y <= self.height as f32 - 1.0 + EPSILON
+    }
+
+    fn sample_bilinear(&self, x: f32, y: f32) -> Color {
+        let x = x.clamp(0.0, self.width as f32 - 1.0);
+        let y = y.clamp(0.0, self.height as f32 - 1.0);
+        let x0 = x.floor() as usize;
+        let y0 = y.floor() as usize;
+        let x1 = (x0 + 1).min(self.width - 1);
+        let y1 = (y0 + 1).min(self.height - 1);
+        let fx = x - x0 as f32;
+        let fy = y - y0 as f32;
+        let a = &self.pixels[y0 * self.width + x0];
+        let b = &self.pixels[y0 * self.width + x1];
+        let c = &self.pixels[y1 * self.width + x0];
+        let d = &self.pixels[y1 * self.width + x1];
+        let mix = |a: u8, b: u8, c: u8, d: u8| {
+            let top = a as f32 + (b as f32 - a as f32) * fx;
+            let bottom = c as f32 + (d as f32 - c as f32) * fx;
+            (top + (bottom - top) * fy).round() as u8
+        };
+        Color::new(
+            mix(a.red, b.red, c.red, d.red),
+            mix(a.green, b.green, c.green, d.green),
+            mix(a.blue, b.blue, c.blue, d.blue),
+        )
+    }
+
+    pub fn resize(&self, width: usize, height: usize) -> Ppm {
+        let mut ppm = Ppm::new(width, height);
+        ppm.color_depth = self.color_depth;
+        if self.pixels.is_empty() {
+            return ppm;
+        }
+        let scale_x = self.width as f32 / width as f32;
+        let scale_y = self.height as f32 / height as f32;
+        for y in 0..height {
+            for x in 0..width {
+                let sx = (x as f32 + 0.5) * scale_x - 0.5;
+                let sy = (y as f32 + 0.5) * scale_y - 0.5;
+                ppm.pixels[y * width + x] = self.sample_bilinear(sx, sy);
+            }
+        }
+        ppm
+    }
+
+    pub fn paste(&mut self, src: &Ppm, x: usize, y: usize) {
+        for sy in 0..src.height.min(self.height.saturating_sub(y)) {
+            for sx in 0..src.width.min(self.width.saturating_sub(x)) {
+                self.pixels[(y + sy) * self.width + x + sx] =
+                    src.pixels[sy * src.width + sx].clone();
+            }
+        }
+    }
+
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Color) {
+        for py in y..(y + height).min(self.height) {
+            for px in x..(x + width).min(self.width) {
+                self.pixels[py * self.width + px] = color.clone();
+            }
+        }
+    }
+
+    pub fn draw_text(&mut self, text: &str, x: usize, y: usize, color: Color) {
+        let mut cx = x;
+        let mut cy = y;
+        for c in text.chars() {
+            if c == '\n' {
+                cx = x;
+                cy += GLYPH_HEIGHT + 1;
+                continue;
+            }
+            let index = match c {
+                ' '..='~' => c as usize - ' ' as usize,
+                _ => '?' as usize - ' ' as usize,
+            };
+            let glyph = &FONT[index * GLYPH_WIDTH..(index + 1) * GLYPH_WIDTH];
+            for (gx, column) in glyph.iter().enumerate() {
+                for gy in 0..GLYPH_HEIGHT {
+                    if column & (1 << gy) != 0 {
+                        self.put_pixel((cx + gx) as isize, (cy + gy) as isize, &color);
+                    }
+                }
+            }
+            cx += GLYPH_ADVANCE;
+        }
+    }
+
+    pub fn contact_sheet(
+        items: &[Ppm],
+        columns: usize,
+        thumb: usize,
+        gap: usize,
+        bg: Color,
+    ) -> Ppm {
+        let items = items.iter().map(|item| (item, None)).collect::<Vec<_>>();
+        Self::sheet(&items, columns, thumb, gap, bg, 0)
+    }
+
+    /// Like `contact_sheet`, but writes each label beneath its thumbnail.
+    /// Labels wider than the thumbnail are truncated.
+    pub fn labeled_contact_sheet(
+        items: &[(Ppm, String)],
+        columns: usize,
+        thumb: usize,
+        gap: usize,
+        bg: Color,
+        text_color: Color,
+    ) -> Ppm {
+        let items = items
+            .iter()
+            .map(|(item, label)| (item, Some((label.as_str(), &text_color))))
+            .collect::<Vec<_>>();
+        Self::sheet(&items, columns, thumb, gap, bg, GLYPH_HEIGHT + 2)
+    }
+
+    fn sheet(
+        items: &[(&Ppm, Option<(&str, &Color)>)],
+        columns: usize,
+        thumb: usize,
+        gap: usize,
+        bg: Color,
+        label_height: usize,
+    ) -> Ppm {
+        let columns = columns.max(1);
+        let rows = items.len().div_ceil(columns);
+        let cell_height = thumb + label_height;
+        let mut sheet = Ppm::new(
+            columns * thumb + (columns + 1) * gap,
+            rows * cell_height + (rows + 1) * gap,
+        );
+        sheet.fill_rect(0, 0, sheet.width, sheet.height, bg);
+        for (i, (item, label)) in items.iter().enumerate() {
+            let x = gap + (i % columns) * (thumb + gap);
+            let y = gap + (i / columns) * (cell_height + gap);
+            if thumb > 0 && !item.pixels.is_empty() {
+                let scale = thumb as f32 / item.width.max(item.height) as f32;
+                let width = ((item.width as f32 * scale).round() as usize).clamp(1, thumb);
+                let height = ((item.height as f32 * scale).round() as usize).clamp(1, thumb);
+                let resized = item.resize(width, height);
+                sheet.paste(&resized, x + (thumb - width) / 2, y + (thumb - height) / 2);
+            }
+            if let Some((text, color)) = label {
+                let max_chars = (thumb + 1) / GLYPH_ADVANCE;
+                let text = text.chars().take(max_chars).collect::<String>();
+                sheet.draw_text(&text, x, y + thumb + 2, (*color).clone());
+            }
+        }
+        sheet
+    }
+
+    fn put_pixel(&mut self, x: isize, y: isize, color: &Color) {
+        if x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height {
+            self.pixels[y as usize * self.width + x as usize] = color.clone();
+        }
+    }
+
+    pub fn gamma(&self, gamma: f32) -> Ppm {
+        self.gamma_rgb(gamma, gamma, gamma)
+    }
+
+    /// Applies a separate gamma to each channel. If any gamma isn't positive
+    /// (or is NaN), the image is returned unchanged.
+    pub fn gamma_rgb(&self, gr: f32, gg: f32, gb: f32) -> Ppm {
+        if [gr, gg, gb]
+            .iter()
+            .any(|gamma| gamma.is_nan() || *gamma <= 0.0)
+        {
+            return self.clone();
+        }
+        let lut = |gamma: f32| {
+            let mut lut = [0u8; 256];
+            for (i, value) in lut.iter_mut().enumerate() {
+                *value = (255.0 * (i as f32 / 255.0).powf(gamma)).round() as u8;
+            }
+            lut
+        };
+        self.apply_luts(&lut(gr), &lut(gg), &lut(gb))
+    }
+
+    fn apply_luts(&self, red: &[u8; 256], green: &[u8; 256], blue: &[u8; 256]) -> Ppm {
+        let mut ppm = self.clone();
+        for pixel in ppm.pixels.iter_mut() {
+            *pixel = Color::new(
+                red[pixel.red as usize],
+                green[pixel.green as usize],
+                blue[pixel.blue as usize],
+            );
+        }
+        ppm
+    }
+
+    pub fn from_str_ascii(s: &str) -> Result<Ppm, Error> {
+        let mut tokens = s
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default())
+            .flat_map(str::split_whitespace);
+        let signature = tokens.next().ok_or(UNEXPECTED_EOF)?;
+        if matches!(signature, "P4" | "P5" | "P6") {
+            return Err(BINARY_FORMAT.into());
+        }
+        if !matches!(signature, "P1" | "P2" | "P3") {
+            return Err(INVALID_SIGNATURE.into());
+        }
+        let next_number = |tokens: &mut dyn Iterator<Item = &str>| {
+            tokens
+                .next()
+                .ok_or(UNEXPECTED_EOF)?
+                .parse::<usize>()
+                .map_err(|_| Error::from(INVALID_FORMAT))
+        };
+        let width = next_number(&mut tokens)?;
+        let height = next_number(&mut tokens)?;
+        let color_depth = match signature {
+            "P1" => 255,
+            _ => next_number(&mut tokens)?,
+        };
+        if color_depth > 255 {
+            return Err(INVALID_FORMAT.into());
+        }
+        width
+            .checked_mul(height)
+            .and_then(|pixels| pixels.checked_mul(3))
+            .ok_or(INVALID_FORMAT)?;
+        let mut ppm = Ppm::new(width, height);
+        ppm.color_depth = color_depth;
+        let mut values: Box<dyn Iterator<Item = &str>> = if signature == "P1" {
+            Box::new(tokens.flat_map(|token| {
+                token
+                    .char_indices()
+                    .map(move |(i, c)| &token[i..i + c.len_utf8()])
+            }))
+        } else {
+            Box::new(tokens)
+        };
+        for pixel in ppm.pixels.iter_mut() {
+            *pixel = match signature {
+                "P1" => match next_number(&mut values)? {
+                    0 => Color::new(255, 255, 255),
+                    1 => Color::new(0, 0, 0),
+                    _ => return Err(INVALID_FORMAT.into()),
+                },
+                "P2" => {
+                    let gray = next_number(&mut values)?.min(color_depth) as u8;
+                    Color::new(gray, gray, gray)
+                }
+                _ => {
+                    let red = next_number(&mut values)?.min(color_depth) as u8;
+                    let green = next_number(&mut values)?.min(color_depth) as u8;
+                    let blue = next_number(&mut values)?.min(color_depth) as u8;
+                    Color::new(red, green, blue)
+                }
+            };
+        }
+        Ok(ppm)
+    }
+
+    /// Grayscale map of visually prominent regions, computed as the
+    /// center-surround luma contrast summed over three surround scales and
+    /// stretched so the most salient pixel is white.
+    pub fn saliency_map(&self) -> Ppm {
+        let luma = self.luma_plane();
+        let size = self.width.max(self.height);
+        let center = box_blur_plane(&luma, self.width, self.height, 1);
+        let mut saliency = vec![0.0; luma.len()];
+        for divisor in [16, 8, 4] {
+            let surround = box_blur_plane(&luma, self.width, self.height, (size / divisor).max(2));
+            for ((value, c), s) in saliency.iter_mut().zip(&center).zip(&surround) {
+                *value += (c - s).abs();
+            }
+        }
+        let max = saliency.iter().cloned().fold(0.0, f32::max);
+        if max > 0.0 {
+            saliency.iter_mut().for_each(|value| *value *= 255.0 / max);
+        }
+        Self::from_plane(self.width, self.height, &saliency)
+    }
+
+    fn luma_plane(&self) -> Vec<f32> {
+        self.pixels
+            .iter()
+            .map(|pixel| pixel.luma() as f32)
+            .collect()
+    }
+
+    fn from_plane(width: usize, height: usize, plane: &[f32]) -> Ppm {
+        let mut ppm = Ppm::new(width, height);
+        for (pixel, value) in ppm.pixels.iter_mut().zip(plane) {
+            let value = value.round().clamp(0.0, 255.0) as u8;
+            *pixel = Color::new(value, value, value);
+        }
+        ppm
+    }
+
+    pub fn crop(&self, x: usize, y: usize, width: usize, height: usize) -> Result<Ppm, Error> {
+        if x + width > self.width || y + height > self.height {
+            return Err(format!(
+                "Crop region ({},{}) {}x{} is out of bounds for image size ({}, {})!",
+                x, y, width, height, self.width, self.height
+            )
+            .into());
+        }
+        let mut ppm = Ppm::new(width, height);
+        ppm.color_depth = self.color_depth;
+        for row in 0..height {
+            let start = (y + row) * self.width + x;
+            ppm.pixels[row * width..(row + 1) * width]
+                .clone_from_slice(&self.pixels[start..start + width]);
+        }
+        Ok(ppm)
+    }
+
+    /// Crops to the `width` x `height` window with the highest total saliency,
+    /// falling back to a center crop when the saliency is flat.
+    pub fn smart_crop(&self, width: usize, height: usize) -> Ppm {
+        let width = width.min(self.width);
+        let height = height.min(self.height);
+        let saliency = self.saliency_map();
+        let mut integral = vec![0u64; (self.width + 1) * (self.height + 1)];
+        for y in 0..self.height {
+            let mut row = 0;
+            for x in 0..self.width {
+                row += saliency.pixels[y * self.width + x].red as u64;
+                integral[(y + 1) * (self.width + 1) + x + 1] =
+                    integral[y * (self.width + 1) + x + 1] + row;
+            }
+        }
+        let mut best = ((self.width - width) / 2, (self.height - height) / 2);
+        let mut best_sum = None;
+        let mut min_sum = None;
+        for y in 0..=self.height - height {
+            for x in 0..=self.width - width {
+                let sum = integral[(y + height) * (self.width + 1) + x + width]
+                    + integral[y * (self.width + 1) + x]
+                    - integral[y * (self.width + 1) + x + width]
+                    - integral[(y + height) * (self.width + 1) + x];
+                if best_sum.is_none_or(|best_sum| sum > best_sum) {
+                    best_sum = Some(sum);
+                    best = (x, y);
+                }
+                min_sum = Some(min_sum.map_or(sum, |min_sum: u64| min_sum.min(sum)));
+            }
+        }
+        if best_sum == min_sum {
+            best = ((self.width - width) / 2, (self.height - height) / 2);
+        }
+        self.crop(best.0, best.1, width, height)
+            .expect("crop window is within bounds")
+    }
+
+    /// Maps every pixel to the nearest palette color, diffusing the error with
+    /// Floyd-Steinberg weights. With `serpentine` the scan direction
+    /// alternates per row and the weights are mirrored on right-to-left rows.
+    pub fn dither_floyd_steinberg(&self, palette: &[Color], serpentine: bool) -> Ppm {
+        if palette.is_empty() {
+            return self.clone();
+        }
+        let mut ppm = self.clone();
+        let mut error = vec![[0.0f32; 3]; self.pixels.len()];
+        let order: Box<dyn Iterator<Item = (usize, usize, &Color)>> = if serpentine {
+            Box::new(self.iter_serpentine())
+        } else {
+            Box::new(self.iter_pixels())
+        };
+        for (x, y, pixel) in order {
+            let i = y * self.width + x;
+            let wanted = [
+                pixel.red as f32 + error[i][0],
+                pixel.green as f32 + error[i][1],
+                pixel.blue as f32 + error[i][2],
+            ];
+            let nearest = palette
+                .iter()
+                .min_by(|a, b| {
+                    let distance = |c: &Color| {
+                        (c.red as f32 - wanted[0]).powi(2)
+                            + (c.green as f32 - wanted[1]).powi(2)
+                            + (c.blue as f32 - wanted[2]).powi(2)
+                    };
+                    distance(a).total_cmp(&distance(b))
+                })
+                .unwrap();
+            let delta = [
+                wanted[0] - nearest.red as f32,
+                wanted[1] - nearest.green as f32,
+                wanted[2] - nearest.blue as f32,
+            ];
+            ppm.pixels[i] = nearest.clone();
+            let direction = if serpentine && y % 2 == 1 { -1 } else { 1 };
+            for (dx, dy, weight) in [(1, 0, 7.0), (-1, 1, 3.0), (0, 1, 5.0), (1, 1, 1.0)] {
+                let nx = x as isize + dx * direction;
+                let ny = y + dy;
+                if nx < 0 || nx as usize >= self.width || ny >= self.height {
+                    continue;
+                }
+                let target = &mut error[ny * self.width + nx as usize];
+                for channel in 0..3 {
+                    target[channel] += delta[channel] * weight / 16.0;
+                }
+            }
+        }
+        ppm
+    }
+
+    pub fn grayscale(&self) -> Ppm {
+        let mut ppm = self.clone();
+        for pixel in ppm.pixels.iter_mut() {
+            let luma = pixel.luma();
+            *pixel = Color::new(luma, luma, luma);
+        }
+        ppm
+    }
+
+    /// Color-accurate grayscale: linearizes sRGB, weights with Rec.709
+    /// luminance coefficients and encodes the result back to sRGB. Slower than
+    /// `grayscale`, which applies Rec.601 weights to the gamma-encoded values.
+    pub fn grayscale_linear(&self) -> Ppm {
+        let mut ppm = self.clone();
+        for pixel in ppm.pixels.iter_mut() {
+            let luminance = 0.2126 * srgb_to_linear(pixel.red)
+                + 0.7152 * srgb_to_linear(pixel.green)
+                + 0.0722 * srgb_to_linear(pixel.blue);
+            let gray = linear_to_srgb(luminance);
+            *pixel = Color::new(gray, gray, gray);
+        }
+        ppm
+    }
+
+    pub fn draw_line(&mut self, x0: usize, y0: usize, x1: usize, y1: usize, color: Color) {
+        self.line(
+            (x0 as isize, y0 as isize),
+            (x1 as isize, y1 as isize),
+            &color,
+        );
+    }
+
+    pub fn draw_marker(
+        &mut self,
+        x: usize,
+        y: usize,
+        size: usize,
+        color: Color,
+        style: MarkerStyle,
+    ) {
+        let (x, y) = (x as isize, y as isize);
+        let r = (size / 2) as isize;
+        match style {
+            MarkerStyle::Cross => {
+                self.line((x - r, y - r), (x + r, y + r), &color);
+                self.line((x - r, y + r), (x + r, y - r), &color);
+            }
+            MarkerStyle::Plus => {
+                self.line((x - r, y), (x + r, y), &color);
+                self.line((x, y - r), (x, y + r), &color);
+            }
+            MarkerStyle::Square => {
+                self.line((x - r, y - r), (x + r, y - r), &color);
+                self.line((x + r, y - r), (x + r, y + r), &color);
+                self.line((x + r, y + r), (x - r, y + r), &color);
+                self.line((x - r, y + r), (x - r, y - r), &color);
+            }
+            MarkerStyle::Circle => {
+                let (mut dx, mut dy, mut err) = (r, 0, 1 - r);
+                while dx >= dy {
+                    for (px, py) in [(dx, dy), (dy, dx), (-dy, dx), (-dx, dy)] {
+                        self.put_pixel(x + px, y + py, &color);
+                        self.put_pixel(x - px, y - py, &color);
+                    }
+                    dy += 1;
+                    if err < 0 {
+                        err += 2 * dy + 1;
+                    } else {
+                        dx -= 1;
+                        err += 2 * (dy - dx) + 1;
+                    }
+                }
+            }
+        }
+    }
+
+    fn line(&mut self, (x0, y0): (isize, isize), (x1, y1): (isize, isize), color: &Color) {
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let sx = if x0 < x1 { 1 } else { -1 };
+        let sy = if y0 < y1 { 1 } else { -1 };
+        let (mut x, mut y, mut err) = (x0, y0, dx + dy);
+        loop {
+            self.put_pixel(x, y, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+    }
+
+    pub fn average_stack(frames: &[Ppm]) -> Result<Ppm, Error> {
+        let first = Self::check_frames(frames)?;
+        let mut sums = vec![[0u64; 3]; first.pixels.len()];
+        for frame in frames {
+            for (sum, pixel) in sums.iter_mut().zip(&frame.pixels) {
+                sum[0] += pixel.red as u64;
+                sum[1] += pixel.green as u64;
+                sum[2] += pixel.blue as u64;
+            }
+        }
+        let count = frames.len() as u64;
+        let mut ppm = first.clone();
+        for (pixel, sum) in ppm.pixels.iter_mut().zip(&sums) {
+            let average = |sum: u64| ((sum + count / 2) / count) as u8;
+            *pixel = Color::new(average(sum[0]), average(sum[1]), average(sum[2]));
+        }
+        Ok(ppm)
+    }
+
+    /// Per-pixel weighted average of `frames`, with `weights` normalized to
+    /// sum to one.
+    pub fn weighted_blend(frames: &[Ppm], weights: &[f32]) -> Result<Ppm, Error> {
+        let first = Self::check_frames(frames)?;
+        if frames.len() != weights.len() {
+            return Err(
+                format!("Got {} weights for {} frames!", weights.len(), frames.len()).into(),
+            );
+        }
+        let total: f32 = weights.iter().sum();
+        if total == 0.0 || !total.is_finite() {
+            return Err("Weights must have a non-zero sum!".into());
+        }
+        let mut sums = vec![[0.0f32; 3]; first.pixels.len()];
+        for (frame, weight) in frames.iter().zip(weights) {
+            for (sum, pixel) in sums.iter_mut().zip(&frame.pixels) {
+                sum[0] += pixel.red as f32 * weight;
+                sum[1] += pixel.green as f32 * weight;
+                sum[2] += pixel.blue as f32 * weight;
+            }
+        }
+        let mut ppm = first.clone();
+        for (pixel, sum) in ppm.pixels.iter_mut().zip(&sums) {
+            let average = |sum: f32| (sum / total).round().clamp(0.0, 255.0) as u8;
+            *pixel = Color::new(average(sum[0]), average(sum[1]), average(sum[2]));
+        }
+        Ok(ppm)
+    }
+
+    /// Grayscale motion history: each step decays the history by `decay` and
+    /// raises it to the luma difference between consecutive frames.
+    pub fn motion_history(frames: &[Ppm], decay: f32) -> Result<Ppm, Error> {
+        let first = Self::check_frames(frames)?;
+        let mut history = vec![0.0f32; first.pixels.len()];
+        for pair in frames.windows(2) {
+            for (value, (a, b)) in history
+                .iter_mut()
+                .zip(pair[0].pixels.iter().zip(&pair[1].pixels))
+            {
+                let difference = a.luma().abs_diff(b.luma()) as f32;
+                *value = (*value * decay).max(difference);
+            }
+        }
+        Ok(Ppm::from_plane(first.width, first.height, &history))
+    }
+
+    /// Red-cyan anaglyph: red from the left image's luma, green and blue
+    /// from the right's.
+    pub fn anaglyph(left: &Ppm, right: &Ppm) -> Result<Ppm, Error> {
+        left.check_same_size(right)?;
+        let mut ppm = left.clone();
+        for (pixel, right) in ppm.pixels.iter_mut().zip(&right.pixels) {
+            let (red, cyan) = (pixel.luma(), right.luma());
+            *pixel = Color::new(red, cyan, cyan);
+        }
+        Ok(ppm)
+    }
+
+    pub fn median_stack(frames: &[Ppm]) -> Result<Ppm, Error> {
+        let first = Self::check_frames(frames)?;
+        let mut ppm = first.clone();
+        let mut values = [Vec::new(), Vec::new(), Vec::new()];
+        for (i, pixel) in ppm.pixels.iter_mut().enumerate() {
+            for channel in values.iter_mut() {
+                channel.clear();
+            }
+            for frame in frames {
+                let color = &frame.pixels[i];
+                values[0].push(color.red);
+                values[1].push(color.green);
+                values[2].push(color.blue);
+            }
+            let [red, green, blue] = values.each_mut().map(|channel| median(channel));
+            *pixel = Color::new(red, green, blue);
+        }
+        Ok(ppm)
+    }
+
+    fn check_frames(frames: &[Ppm]) -> Result<&Ppm, Error> {
+        let first = frames.first().ok_or(NO_FRAMES)?;
+        for frame in &frames[1..] {
+            first.check_same_size(frame)?;
+        }
+        Ok(first)
+    }
+
+    /// Replaces pixels deviating from the median of their 8 neighbors by more
+    /// than `threshold` in any channel. With an even neighbor count the middle
+    /// value closer to the pixel itself is used, so pixels along a genuine
+    /// edge stay put.
+    pub fn remove_outlier_pixels(&self, threshold: u8) -> Ppm {
+        let mut ppm = self.clone();
+        let mut neighbors = [
+            Vec::with_capacity(8),
+            Vec::with_capacity(8),
+            Vec::with_capacity(8),
+        ];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                for channel in neighbors.iter_mut() {
+                    channel.clear();
+                }
+                for dy in -1..=1 {
+                    for dx in -1..=1 {
+                        if dx == 0 && dy == 0 {
+                            continue;
+                        }
+                        let neighbor = self.clamped_pixel(x as isize + dx, y as isize + dy);
+                        neighbors[0].push(neighbor.red);
+                        neighbors[1].push(neighbor.green);
+                        neighbors[2].push(neighbor.blue);
+                    }
+                }
+                let pixel = &self.pixels[y * self.width + x];
+                let [red, green, blue] = [
+                    neighbor_median(&mut neighbors[0], pixel.red),
+                    neighbor_median(&mut neighbors[1], pixel.green),
+                    neighbor_median(&mut neighbors[2], pixel.blue),
+                ];
+                if pixel.red.abs_diff(red) > threshold
+                    || pixel.green.abs_diff(green) > threshold
+                    || pixel.blue.abs_diff(blue) > threshold
+                {
+                    ppm.pixels[y * self.width + x] = Color::new(red, green, blue);
+                }
+            }
+        }
+        ppm
+    }
+
+    fn clamped_pixel(&self, x: isize, y: isize) -> &Color {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        &self.pixels[y * self.width + x]
+    }
+
+    pub fn to_rust_source(&self, const_name: &str) -> String {
+        let mut source = String::new();
+        source.push_str(&format!(
+            "const {}_WIDTH: usize = {};\n",
+            const_name, self.width
+        ));
+        source.push_str(&format!(
+            "const {}_HEIGHT: usize = {};\n",
+            const_name, self.height
+        ));
+        source.push_str(&format!(
+            "const {}: [u8; {}] = [\n",
+            const_name,
+            self.pixels.len() * 3
+        ));
+        for row in self.pixels.chunks(4) {
+            let line = row
+                .iter()
+                .map(|pixel| {
+                    format!(
+                        "0x{:02X}, 0x{:02X}, 0x{:02X},",
+                        pixel.red, pixel.green, pixel.blue
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+            source.push_str(&format!("    {}\n", line));
+        }
+        source.push_str("];\n");
+        source
+    }
+
+    pub fn sobel(&self) -> Ppm {
+        let magnitude = self
+            .energy_plane()
+            .iter()
+            .map(|energy| energy / 4.0)
+            .collect::<Vec<_>>();
+        Self::from_plane(self.width, self.height, &magnitude)
+    }
+
+    /// Binary edge map from the Canny pipeline: Gaussian blur with `sigma`,
+    /// Sobel gradients (scaled so a full black-to-white step measures 255),
+    /// non-maximum suppression and hysteresis between `low` and `high`.
+    pub fn canny(&self, low: u8, high: u8, sigma: f32) -> Ppm {
+        let (width, height) = (self.width, self.height);
+        let mut luma = self.luma_plane();
+        if sigma > 0.0 {
+            luma = gaussian_blur_plane(&luma, width, height, sigma);
+        }
+        let (gx, gy) = sobel_plane(&luma, width, height);
+        let magnitude = gx
+            .iter()
+            .zip(&gy)
+            .map(|(gx, gy)| gx.hypot(*gy) / 4.0)
+            .collect::<Vec<_>>();
+        let at = |x: isize, y: isize| {
+            if x < 0 || y < 0 || x as usize >= width || y as usize >= height {
+                0.0
+            } else {
+                magnitude[y as usize * width + x as usize]
+            }
+        };
+        let mut thin = vec![0.0; magnitude.len()];
+        for y in 0..height {
+            for x in 0..width {
+                let i = y * width + x;
+                let angle = gy[i].atan2(gx[i]).to_degrees().rem_euclid(180.0);
+                let (dx, dy) = if !(22.5..157.5).contains(&angle) {
+                    (1, 0)
+                } else if angle < 67.5 {
+                    (1, 1)
+                } else if angle < 112.5 {
+                    (0, 1)
+                } else {
+                    (-1, 1)
+                };
+                let (x, y) = (x as isize, y as isize);
+                let m = magnitude[i];
+                if m > at(x - dx, y - dy) + 0.5 && m >= at(x + dx, y + dy) - 0.5 {
+                    thin[i] = m;
+                }
+            }
+        }
+        let mut edges = vec![false; thin.len()];
+        let mut stack = Vec::new();
+        for (i, m) in thin.iter().enumerate() {
+            if *m >= high as f32 {
+                edges[i] = true;
+                stack.push(i);
+            }
+        }
+        while let Some(i) = stack.pop() {
+            let (x, y) = ((i % width) as isize, (i / width) as isize);
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let (nx, ny) = (x + dx, y + dy);
+                    if nx < 0 || ny < 0 || nx as usize >= width || ny as usize >= height {
+                        continue;
+                    }
+                    let j = ny as usize * width + nx as usize;
+                    if !edges[j] && thin[j] >= low as f32 {
+                        edges[j] = true;
+                        stack.push(j);
+                    }
+                }
+            }
+        }
+        let mut ppm = Ppm::new(width, height);
+        for (pixel, edge) in ppm.pixels.iter_mut().zip(edges) {
+            if edge {
+                *pixel = Color::new(255, 255, 255);
+            }
+        }
+        ppm
+    }
+
+    /// Divides the image by `flat` normalized to its per-channel mean. Channels
+    /// where the flat frame is zero are left unchanged.
+    pub fn flat_field(&self, flat: &Ppm) -> Result<Ppm, Error> {
+        self.check_same_size(flat)?;
+        let count = flat.pixels.len().max(1) as f32;
+        let mut means = [0.0f32; 3];
+        for pixel in &flat.pixels {
+            means[0] += pixel.red as f32 / count;
+            means[1] += pixel.green as f32 / count;
+            means[2] += pixel.blue as f32 / count;
+        }
+        let correct = |value: u8, flat: u8, mean: f32| {
+            if flat == 0 || mean == 0.0 {
+                value
+            } else {
+                (value as f32 * mean / flat as f32)
+                    .round()
+                    .clamp(0.0, 255.0) as u8
+            }
+        };
+        let mut ppm = self.clone();
+        for (pixel, flat) in ppm.pixels.iter_mut().zip(&flat.pixels) {
+            *pixel = Color::new(
+                correct(pixel.red, flat.red, means[0]),
+                correct(pixel.green, flat.green, means[1]),
+                correct(pixel.blue, flat.blue, means[2]),
+            );
+        }
+        Ok(ppm)
+    }
+
+    /// Unwraps the annulus between `r_inner` and `r_outer` around (`cx`, `cy`)
+    /// into a rectangle with the angle along x and the radius along y. Samples
+    /// falling outside the source are black.
+    pub fn polar_unwrap(
+        &self,
+        cx: usize,
+        cy: usize,
+        r_inner: f32,
+        r_outer: f32,
+        out_w: usize,
+        out_h: usize,
+    ) -> Ppm {
+        let mut ppm = Ppm::new(out_w, out_h);
+        ppm.color_depth = self.color_depth;
+        for y in 0..out_h {
+            let radius = r_inner + (r_outer - r_inner) * (y as f32 + 0.5) / out_h as f32;
+            for x in 0..out_w {
+                let angle = std::f32::consts::TAU * (x as f32 + 0.5) / out_w as f32;
+                let sx = cx as f32 + radius * angle.cos();
+                let sy = cy as f32 + radius * angle.sin();
+                if self.contains(sx, sy) {
+                    ppm.pixels[y * out_w + x] = self.sample_bilinear(sx, sy);
+                }
+            }
+        }
+        ppm
+    }
+
+    pub fn grid_average(&self, cols: usize, rows: usize) -> Ppm {
+        let cols = cols.clamp(1, self.width.max(1));
+        let rows = rows.clamp(1, self.height.max(1));
+        let mut ppm = self.clone();
+        for row in 0..rows {
+            let (y0, y1) = (row * self.height / rows, (row + 1) * self.height / rows);
+            for col in 0..cols {
+                let (x0, x1) = (col * self.width / cols, (col + 1) * self.width / cols);
+                let average = self.average_color(x0, y0, x1 - x0, y1 - y0);
+                ppm.fill_rect(x0, y0, x1 - x0, y1 - y0, average);
+            }
+        }
+        ppm
+    }
+
+    fn average_color(&self, x: usize, y: usize, width: usize, height: usize) -> Color {
+        let mut sum = [0u64; 3];
+        for py in y..y + height {
+            for pixel in &self.pixels[py * self.width + x..py * self.width + x + width] {
+                sum[0] += pixel.red as u64;
+                sum[1] += pixel.green as u64;
+                sum[2] += pixel.blue as u64;
+            }
+        }
+        let count = (width * height).max(1) as u64;
+        let average = |sum: u64| ((sum + count / 2) / count) as u8;
+        Color::new(average(sum[0]), average(sum[1]), average(sum[2]))
+    }
+
+    /// Stretches the value range so the darkest value maps to 0 and the
+    /// brightest to `color_depth`. The range is measured on luma, or on each
+    /// channel separately when `per_channel` is set. Flat images are returned
+    /// unchanged.
+    pub fn normalize(&self, per_channel: bool) -> Ppm {
+        let top = self.color_depth.min(255) as f32;
+        let ranges = if per_channel {
+            let range = |channel: fn(&Color) -> u8| {
+                let values = self.pixels.iter().map(channel);
+                (values.clone().min().unwrap_or(0), values.max().unwrap_or(0))
+            };
+            [range(Color::red), range(Color::green), range(Color::blue)]
+        } else {
+            let luma = self.pixels.iter().map(Color::luma);
+            let range = (luma.clone().min().unwrap_or(0), luma.max().unwrap_or(0));
+            [range; 3]
+        };
+        let lut = |(min, max): (u8, u8)| {
+            let mut lut = [0u8; 256];
+            for (i, value) in lut.iter_mut().enumerate() {
+                *value = if min == max {
+                    i as u8
+                } else {
+                    ((i as f32 - min as f32) * top / (max - min) as f32)
+                        .round()
+                        .clamp(0.0, top) as u8
+                };
+            }
+            lut
+        };
+        self.apply_luts(&lut(ranges[0]), &lut(ranges[1]), &lut(ranges[2]))
+    }
+
+    /// Corrects radial lens distortion by sampling each output pixel at
+    /// `p / (1 + k1 r² + k2 r⁴)`, with `r` normalized to the half-diagonal.
+    /// Positive coefficients correct barrel distortion, negative ones
+    /// pincushion. Pixels mapping outside the source are black.
+    pub fn correct_barrel(&self, k1: f32, k2: f32) -> Ppm {
+        let cx = (self.width as f32 - 1.0) / 2.0;
+        let cy = (self.height as f32 - 1.0) / 2.0;
+        let norm = cx.hypot(cy).max(1.0);
+        let mut ppm = self.clone();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let dx = (x as f32 - cx) / norm;
+                let dy = (y as f32 - cy) / norm;
+                let r2 = dx * dx + dy * dy;
+                let scale = 1.0 / (1.0 + k1 * r2 + k2 * r2 * r2);
+                let sx = cx + dx * scale * norm;
+                let sy = cy + dy * scale * norm;
+                ppm.pixels[y * self.width + x] = if scale.is_finite() && self.contains(sx, sy) {
+                    self.sample_bilinear(sx, sy)
+                } else {
+                    Color::new(0, 0, 0)
+                };
+            }
+        }
+        ppm
+    }
+
+    /// Colors the per-pixel luma difference from blue (unchanged) through
+    /// green to red (largest possible difference).
+    pub fn diff_heatmap(&self, other: &Ppm) -> Result<Ppm, Error> {
+        self.check_same_size(other)?;
+        let mut ppm = self.clone();
+        for (pixel, other) in ppm.pixels.iter_mut().zip(&other.pixels) {
+            let difference = pixel.luma().abs_diff(other.luma());
+            *pixel = heat_color(difference as f32 / 255.0);
+        }
+        Ok(ppm)
+    }
+
+    pub fn from_grayscale_bands(r: &Ppm, g: &Ppm, b: &Ppm) -> Result<Ppm, Error> {
+        r.check_same_size(g)?;
+        r.check_same_size(b)?;
+        let mut ppm = r.clone();
+        for ((pixel, g), b) in ppm.pixels.iter_mut().zip(&g.pixels).zip(&b.pixels) {
+            *pixel = Color::new(pixel.luma(), g.luma(), b.luma());
+        }
+        Ok(ppm)
+    }
+
+    pub fn flip_horizontal(&self) -> Ppm {
+        self.orient(Orientation::FlipHorizontal)
+    }
+
+    pub fn flip_vertical(&self) -> Ppm {
+        self.orient(Orientation::FlipVertical)
+    }
+
+    pub fn rotate90(&self) -> Ppm {
+        self.orient(Orientation::Rotate90)
+    }
+
+    pub fn rotate180(&self) -> Ppm {
+        self.orient(Orientation::Rotate180)
+    }
+
+    pub fn rotate270(&self) -> Ppm {
+        self.orient(Orientation::Rotate270)
+    }
+
+    /// Applies one of the eight dihedral orientations. Rotations are clockwise.
+    pub fn orient(&self, orientation: Orientation) -> Ppm {
+        let (w, h) = (self.width, self.height);
+        let swapped = matches!(
+            orientation,
+            Orientation::Rotate90
+                | Orientation::Rotate270
+                | Orientation::Transpose
+                | Orientation::Transverse
+        );
+        let (width, height) = if swapped { (h, w) } else { (w, h) };
+        let mut ppm = Ppm::new(width, height);
+        ppm.color_depth = self.color_depth;
+        for y in 0..height {
+            for x in 0..width {
+                let (sx, sy) = match orientation {
+                    Orientation::Identity => (x, y),
+                    Orientation::Rotate90 => (y, h - 1 - x),
+                    Orientation::Rotate180 => (w - 1 - x, h - 1 - y),
+                    Orientation::Rotate270 => (w - 1 - y, x),
+                    Orientation::FlipHorizontal => (w - 1 - x, y),
+                    Orientation::FlipVertical => (x, h - 1 - y),
+                    Orientation::Transpose => (y, x),
+                    Orientation::Transverse => (w - 1 - y, h - 1 - x),
+                };
+                ppm.pixels[y * width + x] = self.pixels[sy * w + sx].clone();
+            }
+        }
+        ppm
+    }
+
+    /// Applies the transform that displays an image stored with the given
+    /// EXIF orientation tag upright. Codes outside 1-8 leave it unchanged.
+    pub fn apply_exif_orientation(&self, orientation: u8) -> Ppm {
+        self.orient(match orientation {
+            2 => Orientation::FlipHorizontal,
+            3 => Orientation::Rotate180,
+            4 => Orientation::FlipVertical,
+            5 => Orientation::Transpose,
+            6 => Orientation::Rotate90,
+            7 => Orientation::Transverse,
+            8 => Orientation::Rotate270,
+            _ => Orientation::Identity,
+        })
+    }
+
+    /// Finds the orientation that turns `reference` into this image, i.e. the
+    /// one with the lowest mean absolute channel difference between
+    /// `reference.orient(orientation)` and `self`. Returns `None` if even the
+    /// best candidate differs by more than 8 levels on average.
+    pub fn detect_orientation(&self, reference: &Ppm) -> Option<Orientation> {
+        let mut best = None;
+        for orientation in Orientation::ALL {
+            let candidate = reference.orient(orientation);
+            if candidate.width != self.width || candidate.height != self.height {
+                continue;
+            }
+            let total = self
+                .pixels
+                .iter()
+                .zip(&candidate.pixels)
+                .map(|(a, b)| {
+                    a.red.abs_diff(b.red) as u64
+                        + a.green.abs_diff(b.green) as u64
+                        + a.blue.abs_diff(b.blue) as u64
+                })
+                .sum::<u64>();
+            let difference = total as f64 / (self.pixels.len().max(1) * 3) as f64;
+            if best.is_none_or(|(_, best)| difference < best) {
+                best = Some((orientation, difference));
+            }
+        }
+        best.filter(|(_, difference)| *difference <= ORIENTATION_MAX_DIFFERENCE)
+            .map(|(orientation, _)| orientation)
+    }
+
+    /// Labels 4-connected regions of non-`background` pixels. The result holds
+    /// one label per pixel, 0 for background and 1..=n for the components in
+    /// scan order.
+    pub fn label_components(&self, background: Color) -> Vec<usize> {
+        self.label_where(|pixel| *pixel != background)
+    }
+
+    fn label_where(&self, foreground: impl Fn(&Color) -> bool) -> Vec<usize> {
+        let mut labels = vec![0; self.pixels.len()];
+        let mut next = 0;
+        let mut stack = Vec::new();
+        for start in 0..self.pixels.len() {
+            if labels[start] != 0 || !foreground(&self.pixels[start]) {
+                continue;
+            }
+            next += 1;
+            labels[start] = next;
+            stack.push(start);
+            while let Some(i) = stack.pop() {
+                let (x, y) = (i % self.width, i / self.width);
+                let mut neighbors = Vec::with_capacity(4);
+                if x > 0 {
+                    neighbors.push(i - 1);
+                }
+                if x + 1 < self.width {
+                    neighbors.push(i + 1);
+                }
+                if y > 0 {
+                    neighbors.push(i - self.width);
+                }
+                if y + 1 < self.height {
+                    neighbors.push(i + self.width);
+                }
+                for j in neighbors {
+                    if labels[j] == 0 && foreground(&self.pixels[j]) {
+                        labels[j] = next;
+                        stack.push(j);
+                    }
+                }
+            }
+        }
+        labels
+    }
+
+    /// Bounding box `(x, y, width, height)` of every component found by
+    /// `label_components`, in label order.
+    pub fn component_bounding_boxes(&self, background: Color) -> Vec<(usize, usize, usize, usize)> {
+        self.bounding_boxes(&self.label_components(background))
+    }
+
+    fn bounding_boxes(&self, labels: &[usize]) -> Vec<(usize, usize, usize, usize)> {
+        let mut boxes: Vec<(usize, usize, usize, usize)> = Vec::new();
+        for (i, label) in labels.iter().enumerate() {
+            if *label == 0 {
+                continue;
+            }
+            let (x, y) = (i % self.width, i / self.width);
+            if *label > boxes.len() {
+                boxes.push((x, y, x, y));
+            }
+            let bounds = &mut boxes[label - 1];
+            bounds.0 = bounds.0.min(x);
+            bounds.1 = bounds.1.min(y);
+            bounds.2 = bounds.2.max(x);
+            bounds.3 = bounds.3.max(y);
+        }
+        boxes
+            .into_iter()
+            .map(|(x0, y0, x1, y1)| (x0, y0, x1 - x0 + 1, y1 - y0 + 1))
+            .collect()
+    }
+
+    /// Crops to the bounding box of the largest 4-connected region of pixels
+    /// that differ from `background` by more than `tolerance` in any channel.
+    pub fn largest_content_rect(&self, background: Color, tolerance: u8) -> Option<Ppm> {
+        let (labels, label) = self.largest_region(&background, tolerance)?;
+        let (x, y, width, height) = self.bounding_boxes(&labels)[label - 1];
+        self.crop(x, y, width, height).ok()
+    }
+
+    /// Labels the regions not within `tolerance` of `background`, returning
+    /// the labels and the label of the region with the most pixels.
+    fn largest_region(&self, background: &Color, tolerance: u8) -> Option<(Vec<usize>, usize)> {
+        let labels = self.label_where(|pixel| !pixel.is_close(background, tolerance));
+        let mut sizes = vec![0usize; labels.iter().max().map_or(0, |max| max + 1)];
+        for &label in &labels {
+            sizes[label] += 1;
+        }
+        let (label, _) = sizes
+            .iter()
+            .enumerate()
+            .skip(1)
+            .max_by_key(|&(label, size)| (size, std::cmp::Reverse(label)))?;
+        Some((labels, label))
+    }
+
+    /// Sigmas that aren't positive and finite leave the image unchanged.
+    pub fn bilateral_filter(&self, spatial_sigma: f32, range_sigma: f32) -> Ppm {
+        let valid = |sigma: f32| sigma > 0.0 && sigma.is_finite();
+        if !valid(spatial_sigma) || !valid(range_sigma) {
+            return self.clone();
+        }
+        let radius = ((2.0 * spatial_sigma).ceil().max(1.0) as isize)
+            .min(self.width.max(self.height) as isize);
+        let spatial = 2.0 * spatial_sigma * spatial_sigma;
+        let range = 2.0 * range_sigma * range_sigma;
+        let mut ppm = self.clone();
+        for y in 0..self.height as isize {
+            for x in 0..self.width as isize {
+                let center = self.clamped_pixel(x, y);
+                let mut sum = [0.0f32; 3];
+                let mut total = 0.0;
+                for dy in -radius..=radius {
+                    for dx in -radius..=radius {
+                        let neighbor = self.clamped_pixel(x + dx, y + dy);
+                        let distance = (neighbor.red as f32 - center.red as f32).powi(2)
+                            + (neighbor.green as f32 - center.green as f32).powi(2)
+                            + (neighbor.blue as f32 - center.blue as f32).powi(2);
+                        let weight =
+                            (-((dx * dx + dy * dy) as f32) / spatial - distance / range).exp();
+                        sum[0] += neighbor.red as f32 * weight;
+                        sum[1] += neighbor.green as f32 * weight;
+                        sum[2] += neighbor.blue as f32 * weight;
+                        total += weight;
+                    }
+                }
+                ppm.pixels[y as usize * self.width + x as usize] = Color::new(
+                    (sum[0] / total).round() as u8,
+                    (sum[1] / total).round() as u8,
+                    (sum[2] / total).round() as u8,
+                );
+            }
+        }
+        ppm
+    }
+
+    /// Filters the luma channel in the frequency domain with a Gaussian mask
+    /// and returns it as a grayscale image. `cutoff` is relative to the
+    /// Nyquist frequency (1.0). The image is padded with its mean luma to
+    /// power-of-two dimensions for the FFT. High-pass output has no DC
+    /// component, so it is offset by 128.
+    pub fn frequency_filter(&self, cutoff: f32, high_pass: bool) -> Ppm {
+        if self.pixels.is_empty() {
+            return self.clone();
+        }
+        let (width, height) = (
+            self.width.next_power_of_two(),
+            self.height.next_power_of_two(),
+        );
+        let luma = self.luma_plane();
+        let mean = luma.iter().map(|value| *value as f64).sum::<f64>() / luma.len() as f64;
+        let mut data = vec![(mean, 0.0); width * height];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                data[y * width + x].0 = luma[y * self.width + x] as f64;
+            }
+        }
+        fft_2d(&mut data, width, height, false);
+        let cutoff = (cutoff as f64).max(f64::EPSILON);
+        for y in 0..height {
+            let fy = y.min(height - y) as f64 / height as f64 * 2.0;
+            for x in 0..width {
+                let fx = x.min(width - x) as f64 / width as f64 * 2.0;
+                let mut gain = (-(fx * fx + fy * fy) / (2.0 * cutoff * cutoff)).exp();
+                if high_pass {
+                    gain = 1.0 - gain;
+                }
+                let value = &mut data[y * width + x];
+                *value = (value.0 * gain, value.1 * gain);
+            }
+        }
+        fft_2d(&mut data, width, height, true);
+        let offset = if high_pass { 128.0 } else { 0.0 };
+        let mut plane = vec![0.0; self.pixels.len()];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                plane[y * self.width + x] = (data[y * width + x].0 + offset) as f32;
+            }
+        }
+        Self::from_plane(self.width, self.height, &plane)
+    }
+
+    pub fn draw_scalebar(
+        &mut self,
+        length_px: usize,
+        x: usize,
+        y: usize,
+        thickness: usize,
+        color: Color,
+    ) {
+        self.fill_rect(x, y, length_px, thickness, color);
+    }
+
+    /// Scalebar divided into `divisions` equal parts, with a tick mark of
+    /// `thickness` pixels rising above the bar at every division boundary.
+    pub fn draw_scalebar_with_ticks(
+        &mut self,
+        length_px: usize,
+        x: usize,
+        y: usize,
+        thickness: usize,
+        divisions: usize,
+        color: Color,
+    ) {
+        self.draw_scalebar(length_px, x, y, thickness, color.clone());
+        if length_px == 0 {
+            return;
+        }
+        let divisions = divisions.max(1);
+        let top = y.saturating_sub(thickness);
+        for i in 0..=divisions {
+            let tick = x + (i * (length_px - 1)) / divisions;
+            self.fill_rect(tick, top, 1, y - top, color.clone());
+        }
+    }
+
+    /// Keeps the top `bits` bits (1 to 8) of every channel and fills the low
+    /// bits by replicating the kept ones, so full intensity stays at 0xFF
+    /// (with `bits` = 4, 0xFF stays 0xFF and 0x9C becomes 0x99).
+    pub fn reduce_bit_depth(&self, bits: u8) -> Ppm {
+        let bits = bits.clamp(1, 8) as u32;
+        let mut lut = [0u8; 256];
+        for (i, value) in lut.iter_mut().enumerate() {
+            let kept = (i as u8) >> (8 - bits);
+            let mut replicated = 0u32;
+            let mut filled = 0;
+            while filled < 8 {
+                replicated = (replicated << bits) | kept as u32;
+                filled += bits;
+            }
+            *value = (replicated >> (filled - 8)) as u8;
+        }
+        self.apply_luts(&lut, &lut, &lut)
+    }
+
+    pub fn luma_histogram(&self) -> [usize; 256] {
+        let mut histogram = [0; 256];
+        for pixel in &self.pixels {
+            histogram[pixel.luma() as usize] += 1;
+        }
+        histogram
+    }
+
+    /// Shannon entropy of the luma histogram in bits, from 0 for a uniform
+    /// image up to 8.
+    pub fn entropy(&self) -> f64 {
+        let total = self.pixels.len() as f64;
+        self.luma_histogram()
+            .iter()
+            .filter(|count| **count > 0)
+            .map(|count| {
+                let p = *count as f64 / total;
+                p * (1.0 / p).log2()
+            })
+            .sum()
+    }
+
+    /// Sauvola binarization: each pixel is white if its luma exceeds
+    /// `m * (1 + k * (s / 128 - 1))`, where `m` and `s` are the mean and
+    /// standard deviation of the surrounding `window` x `window` area.
+    pub fn adaptive_threshold(&self, window: usize, k: f32) -> Ppm {
+        let (width, height) = (self.width, self.height);
+        let stride = width + 1;
+        let mut sum = vec![0.0f64; stride * (height + 1)];
+        let mut squares = vec![0.0f64; stride * (height + 1)];
+        for y in 0..height {
+            let (mut row, mut row_squares) = (0.0, 0.0);
+            for x in 0..width {
+                let luma = self.pixels[y * width + x].luma() as f64;
+                row += luma;
+                row_squares += luma * luma;
+                sum[(y + 1) * stride + x + 1] = sum[y * stride + x + 1] + row;
+                squares[(y + 1) * stride + x + 1] = squares[y * stride + x + 1] + row_squares;
+            }
+        }
+        let radius = window / 2;
+        let mut ppm = Ppm::new(width, height);
+        for y in 0..height {
+            let (y0, y1) = (y.saturating_sub(radius), (y + radius + 1).min(height));
+            for x in 0..width {
+                let (x0, x1) = (x.saturating_sub(radius), (x + radius + 1).min(width));
+                let area = |table: &[f64]| {
+                    table[y1 * stride + x1] - table[y0 * stride + x1] - table[y1 * stride + x0]
+                        + table[y0 * stride + x0]
+                };
+                let count = ((x1 - x0) * (y1 - y0)) as f64;
+                let mean = area(&sum) / count;
+                let deviation = (area(&squares) / count - mean * mean).max(0.0).sqrt();
+                let threshold = mean * (1.0 + k as f64 * (deviation / 128.0 - 1.0));
+                if self.pixels[y * width + x].luma() as f64 > threshold {
+                    ppm.pixels[y * width + x] = Color::new(255, 255, 255);
+                }
+            }
+        }
+        ppm
+    }
+
+    /// Maps each pixel to the closest palette color, measured either as
+    /// Euclidean RGB distance or as CIEDE2000 difference.
+    pub fn quantize(&self, palette: &[Color], distance: ColorDistance) -> Ppm {
+        if palette.is_empty() {
+            return self.clone();
+        }
+        let metric = |a: &Color, b: &Color| match distance {
+            ColorDistance::Rgb => ((a.red as f64 - b.red as f64).powi(2)
+                + (a.green as f64 - b.green as f64).powi(2)
+                + (a.blue as f64 - b.blue as f64).powi(2))
+            .sqrt(),
+            ColorDistance::Perceptual => a.delta_e(b),
+        };
+        let mut cache = HashMap::new();
+        let mut ppm = self.clone();
+        for pixel in ppm.pixels.iter_mut() {
+            let nearest = cache.entry(pixel.clone()).or_insert_with(|| {
+                palette
+                    .iter()
+                    .min_by(|a, b| metric(pixel, a).total_cmp(&metric(pixel, b)))
+                    .unwrap()
+                    .clone()
+            });
+            *pixel = nearest.clone();
+        }
+        ppm
+    }
+
+    /// Content-aware narrowing: repeatedly removes the vertical seam with the
+    /// lowest total Sobel gradient energy until `new_width` is reached.
+    pub fn seam_carve(&self, new_width: usize) -> Ppm {
+        let mut ppm = self.clone();
+        while ppm.width > new_width && ppm.height > 0 {
+            let (width, height) = (ppm.width, ppm.height);
+            let energy = ppm.energy_plane();
+            let mut cost = energy.clone();
+            for y in 1..height {
+                for x in 0..width {
+                    let above = &cost[(y - 1) * width..y * width];
+                    let best = above[x.saturating_sub(1)..(x + 2).min(width)]
+                        .iter()
+                        .cloned()
+                        .fold(f32::INFINITY, f32::min);
+                    cost[y * width + x] += best;
+                }
+            }
+            let last = &cost[(height - 1) * width..];
+            let mut x = (0..width)
+                .min_by(|a, b| last[*a].total_cmp(&last[*b]))
+                .unwrap_or(0);
+            let mut seam = vec![0; height];
+            for y in (0..height).rev() {
+                seam[y] = x;
+                if y > 0 {
+                    let row = &cost[(y - 1) * width..y * width];
+                    x = (x.saturating_sub(1)..(x + 2).min(width))
+                        .min_by(|a, b| row[*a].total_cmp(&row[*b]))
+                        .unwrap_or(x);
+                }
+            }
+            let mut pixels = Vec::with_capacity((width - 1) * height);
+            for (y, skip) in seam.iter().enumerate() {
+                for x in 0..width {
+                    if x != *skip {
+                        pixels.push(ppm.pixels[y * width + x].clone());
+                    }
+                }
+            }
+            ppm.width -= 1;
+            ppm.pixels = pixels;
+        }
+        ppm
+    }
+
+    pub fn seam_carve_height(&self, new_height: usize) -> Ppm {
+        self.orient(Orientation::Transpose)
+            .seam_carve(new_height)
+            .orient(Orientation::Transpose)
+    }
+
+    /// Grayscale view of the gradient energy used by `seam_carve`, scaled so a
+    /// full black-to-white step reaches 255.
+    pub fn energy_map(&self) -> Ppm {
+        self.sobel()
+    }
+
+    fn energy_plane(&self) -> Vec<f32> {
+        let (gx, gy) = sobel_plane(&self.luma_plane(), self.width, self.height);
+        gx.iter().zip(&gy).map(|(gx, gy)| gx.hypot(*gy)).collect()
+    }
+
+    /// Returns the image with a mask marking every pixel whose channels are all
+    /// within `tolerance` of `key` as background (`true`).
+    pub fn chroma_key(&self, key: Color, tolerance: u8) -> (Ppm, Vec<bool>) {
+        let mask = self
+            .pixels
+            .iter()
+            .map(|pixel| pixel.is_close(&key, tolerance))
+            .collect();
+        (self.clone(), mask)
+    }
+
+    pub fn box_blur(&self, radius: usize) -> Ppm {
+        let [red, green, blue] = self
+            .channel_planes()
+            .map(|plane| box_blur_plane(&plane, self.width, self.height, radius));
+        self.with_channel_planes(&red, &green, &blue)
+    }
+
+    /// Box-blurs the image and blends the result in proportionally to the
+    /// mask luma, so black mask pixels stay sharp and white ones are fully
+    /// blurred.
+    pub fn blur_masked(&self, radius: usize, mask: &Ppm) -> Result<Ppm, Error> {
+        self.check_same_size(mask)?;
+        let blurred = self.box_blur(radius);
+        let mut ppm = self.clone();
+        for ((pixel, blurred), mask) in ppm.pixels.iter_mut().zip(&blurred.pixels).zip(&mask.pixels)
+        {
+            let alpha = mask.luma() as f32 / 255.0;
+            let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * alpha).round() as u8;
+            *pixel = Color::new(
+                mix(pixel.red, blurred.red),
+                mix(pixel.green, blurred.green),
+                mix(pixel.blue, blurred.blue),
+            );
+        }
+        Ok(ppm)
+    }
+
+    fn channel_planes(&self) -> [Vec<f32>; 3] {
+        [
+            self.pixels.iter().map(|pixel| pixel.red as f32).collect(),
+            self.pixels.iter().map(|pixel| pixel.green as f32).collect(),
+            self.pixels.iter().map(|pixel| pixel.blue as f32).collect(),
+        ]
+    }
+
+    fn with_channel_planes(&self, red: &[f32], green: &[f32], blue: &[f32]) -> Ppm {
+        let channel = |value: f32| value.round().clamp(0.0, 255.0) as u8;
+        let mut ppm = self.clone();
+        for (i, pixel) in ppm.pixels.iter_mut().enumerate() {
+            *pixel = Color::new(channel(red[i]), channel(green[i]), channel(blue[i]));
+        }
+        ppm
+    }
+
+    /// Pen-and-ink rendering on white: darker pixels get more hatch layers, up
+    /// to `levels`, drawn as lines every `spacing` pixels. The layers cycle
+    /// through both diagonals, horizontals and verticals, shifting by half the
+    /// spacing on each further cycle.
+    pub fn crosshatch(&self, levels: usize, spacing: usize) -> Ppm {
+        let spacing = spacing.max(1);
+        let mut ppm = self.clone();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let i = y * self.width + x;
+                let darkness = 1.0 - self.pixels[i].luma() as f32 / 255.0;
+                let layers = (darkness * levels as f32).round() as usize;
+                let inked = (0..layers).any(|layer| {
+                    let offset = (layer / 4) * spacing / 2;
+                    let position = match layer % 4 {
+                        0 => x + y,
+                        1 => x + self.height - y,
+                        2 => y,
+                        _ => x,
+                    };
+                    (position + offset).is_multiple_of(spacing)
+                });
+                ppm.pixels[i] = if inked {
+                    Color::new(0, 0, 0)
+                } else {
+                    Color::new(255, 255, 255)
+                };
+            }
+        }
+        ppm
+    }
+
+    /// Renders the image over `bg` with a blurred `shadow_color` silhouette
+    /// shifted by `offset`. Pixels equal to `bg` count as transparent, and the
+    /// canvas grows to fit the shadow.
+    pub fn drop_shadow(
+        &self,
+        offset: (i32, i32),
+        blur_radius: usize,
+        shadow_color: Color,
+        bg: Color,
+    ) -> Ppm {
+        let (ox, oy) = (offset.0 as isize, offset.1 as isize);
+        let r = blur_radius as isize;
+        let (left, top) = ((-ox).max(0) + r, (-oy).max(0) + r);
+        let width = self.width + (left + ox.max(0) + r) as usize;
+        let height = self.height + (top + oy.max(0) + r) as usize;
+        let mut alpha = vec![0.0; width * height];
+        for (x, y, pixel) in self.iter_pixels() {
+            if *pixel != bg {
+                let sx = (x as isize + left + ox) as usize;
+                let sy = (y as isize + top + oy) as usize;
+                alpha[sy * width + sx] = 1.0;
+            }
+        }
+        let alpha = box_blur_plane(&alpha, width, height, blur_radius);
+        let mut ppm = Ppm::new(width, height);
+        ppm.color_depth = self.color_depth;
+        for (pixel, alpha) in ppm.pixels.iter_mut().zip(alpha) {
+            let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * alpha).round() as u8;
+            *pixel = Color::new(
+                mix(bg.red, shadow_color.red),
+                mix(bg.green, shadow_color.green),
+                mix(bg.blue, shadow_color.blue),
+            );
+        }
+        for (x, y, pixel) in self.iter_pixels() {
+            if *pixel != bg {
+                ppm.pixels[(y + top as usize) * width + x + left as usize] = pixel.clone();
+            }
+        }
+        ppm
+    }
+
+    /// Per-channel contrast stretch that ignores the darkest and brightest
+    /// `clip_percent` of values, mapping the remaining range to full scale.
+    pub fn auto_levels(&self, clip_percent: f32) -> Ppm {
+        let top = self.color_depth.min(255) as f32;
+        let clip = (clip_percent.clamp(0.0, 50.0) / 100.0 * self.pixels.len() as f32) as usize;
+        let lut = |channel: fn(&Color) -> u8| {
+            let mut histogram = [0usize; 256];
+            for pixel in &self.pixels {
+                histogram[channel(pixel) as usize] += 1;
+            }
+            let mut seen = 0;
+            let low = histogram
+                .iter()
+                .position(|count| {
+                    seen += count;
+                    seen > clip
+                })
+                .unwrap_or(0);
+            seen = 0;
+            let high = 255
+                - histogram
+                    .iter()
+                    .rev()
+                    .position(|count| {
+                        seen += count;
+                        seen > clip
+                    })
+                    .unwrap_or(0);
+            let mut lut = [0u8; 256];
+            for (i, value) in lut.iter_mut().enumerate() {
+                *value = if low >= high {
+                    i as u8
+                } else {
+                    ((i as f32 - low as f32) * top / (high - low) as f32)
+                        .round()
+                        .clamp(0.0, top) as u8
+                };
+            }
+            lut
+        };
+        self.apply_luts(&lut(Color::red), &lut(Color::green), &lut(Color::blue))
+    }
+
+    /// Joins `other` to the right of this image, cross-fading linearly across
+    /// `overlap` shared columns.
+    pub fn stitch_horizontal(&self, other: &Ppm, overlap: usize) -> Result<Ppm, Error> {
+        if self.height != other.height {
+            return Err(format!(
+                "Image heights {} and {} don't match!",
+                self.height, other.height
+            )
+            .into());
+        }
+        if overlap > self.width || overlap > other.width {
+            return Err(format!("Overlap of {} columns exceeds an image width!", overlap).into());
+        }
+        let width = self.width + other.width - overlap;
+        let mut ppm = Ppm::new(width, self.height);
+        ppm.color_depth = self.color_depth;
+        ppm.paste(self, 0, 0);
+        ppm.paste(other, self.width - overlap, 0);
+        for y in 0..self.height {
+            for i in 0..overlap {
+                let alpha = (i + 1) as f32 / (overlap + 1) as f32;
+                let a = &self.pixels[y * self.width + self.width - overlap + i];
+                let b = &other.pixels[y * other.width + i];
+                let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * alpha).round() as u8;
+                ppm.pixels[y * width + self.width - overlap + i] = Color::new(
+                    mix(a.red, b.red),
+                    mix(a.green, b.green),
+                    mix(a.blue, b.blue),
+                );
+            }
+        }
+        Ok(ppm)
+    }
+
+    /// Resizes the image, keeping its aspect ratio, to the largest size whose
+    /// `to_bytes` output fits into `max_bytes`, never going below 1x1.
+    /// Images already within budget are returned as is, and if even a 1x1
+    /// image doesn't fit, the 1x1 image is returned anyway.
+    pub fn downscale_to_budget(&self, max_bytes: usize) -> Ppm {
+        let encoded_len = |width: usize, height: usize| {
+            format!("P6\n{} {}\n{}\n", width, height, self.color_depth).len() + width * height * 3
+        };
+        if encoded_len(self.width, self.height) <= max_bytes || self.pixels.is_empty() {
+            return self.clone();
+        }
+        let budget = max_bytes.saturating_sub(encoded_len(0, 0)) as f64 / 3.0;
+        let scale = (budget / self.pixels.len() as f64).sqrt().min(1.0);
+        let mut width = ((self.width as f64 * scale).floor() as usize).max(1);
+        let mut height = ((self.height as f64 * scale).floor() as usize).max(1);
+        while (width > 1 || height > 1) && encoded_len(width, height) > max_bytes {
+            if self.width >= self.height {
+                width -= 1;
+                height = height
+                    .min((width * self.height).div_ceil(self.width))
+                    .max(1);
+            } else {
+                height -= 1;
+                width = width
+                    .min((height * self.width).div_ceil(self.height))
+                    .max(1);
+            }
+        }
+        self.resize(width, height)
+    }
+
+    /// Averages `length` samples along a line through each pixel at `angle`
+    /// degrees, clamping samples to the image edges.
+    pub fn motion_blur(&self, length: usize, angle: f32) -> Ppm {
+        if length <= 1 || self.pixels.is_empty() {
+            return self.clone();
+        }
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let half = (length - 1) as f32 / 2.0;
+        let mut ppm = self.clone();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let mut sum = [0.0f32; 3];
+                for i in 0..length {
+                    let t = i as f32 - half;
+                    let sample = self.sample_bilinear(x as f32 + t * cos, y as f32 + t * sin);
+                    sum[0] += sample.red as f32;
+                    sum[1] += sample.green as f32;
+                    sum[2] += sample.blue as f32;
+                }
+                let average = |sum: f32| (sum / length as f32).round() as u8;
+                ppm.pixels[y * self.width + x] =
+                    Color::new(average(sum[0]), average(sum[1]), average(sum[2]));
+            }
+        }
+        ppm
+    }
+
+    /// Draws `values` as a line graph inside the given rectangle, scaled so the
+    /// smallest value touches the bottom edge and the largest the top edge.
+    pub fn plot_series(
+        &mut self,
+        values: &[f32],
+        x: usize,
+        y: usize,
+        w: usize,
+        h: usize,
+        color: Color,
+    ) {
+        if values.is_empty() || w == 0 || h == 0 {
+            return;
+        }
+        let min = values.iter().cloned().fold(f32::INFINITY, f32::min);
+        let max = values.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let point = |i: usize, value: f32| {
+            let px = if values.len() > 1 {
+                x + i * (w - 1) / (values.len() - 1)
+            } else {
+                x
+            };
+            let t = if max > min {
+                (value - min) / (max - min)
+            } else {
+                0.5
+            };
+            let py = y + h - 1 - (t * (h - 1) as f32).round() as usize;
+            (px, py)
+        };
+        let mut previous = point(0, values[0]);
+        self.draw_line(
+            previous.0,
+            previous.1,
+            previous.0,
+            previous.1,
+            color.clone(),
+        );
+        for (i, value) in values.iter().enumerate().skip(1) {
+            let current = point(i, *value);
+            self.draw_line(previous.0, previous.1, current.0, current.1, color.clone());
+            previous = current;
+        }
+    }
+
+    /// Difference score that down-weights changes near edges, where they are
+    /// harder to notice. Each pixel's largest channel difference is scaled by
+    /// `1 / (1 + e / 32)`, with `e` the local gradient energy of `self` on the
+    /// `energy_map` scale. Returns the mean weighted difference and a
+    /// grayscale image of the weighted differences.
+    pub fn perceptual_diff(&self, other: &Ppm) -> Result<(f64, Ppm), Error> {
+        self.check_same_size(other)?;
+        let energy = self.energy_plane();
+        let weighted = self
+            .pixels
+            .iter()
+            .zip(&other.pixels)
+            .zip(&energy)
+            .map(|((a, b), energy)| {
+                let difference = a
+                    .red
+                    .abs_diff(b.red)
+                    .max(a.green.abs_diff(b.green))
+                    .max(a.blue.abs_diff(b.blue));
+                difference as f32 / (1.0 + energy / 4.0 / 32.0)
+            })
+            .collect::<Vec<_>>();
+        let score =
+            weighted.iter().map(|value| *value as f64).sum::<f64>() / weighted.len().max(1) as f64;
+        Ok((score, Self::from_plane(self.width, self.height, &weighted)))
+    }
+
+    /// Renders a matrix of modules (`true` = `fg`) as `module_size` pixel
+    /// squares, surrounded by a quiet zone that is `quiet_zone` modules wide.
+    pub fn from_module_grid(
+        modules: &[Vec<bool>],
+        module_size: usize,
+        quiet_zone: usize,
+        fg: Color,
+        bg: Color,
+    ) -> Ppm {
+        let columns = modules.iter().map(Vec::len).max().unwrap_or(0);
+        let width = (columns + 2 * quiet_zone) * module_size;
+        let height = (modules.len() + 2 * quiet_zone) * module_size;
+        let mut ppm = Ppm::new(width, height);
+        ppm.fill_rect(0, 0, width, height, bg);
+        for (row, line) in modules.iter().enumerate() {
+            for (col, module) in line.iter().enumerate() {
+                if *module {
+                    ppm.fill_rect(
+                        (col + quiet_zone) * module_size,
+                        (row + quiet_zone) * module_size,
+                        module_size,
+                        module_size,
+                        fg.clone(),
+                    );
+                }
+            }
+        }
+        ppm
+    }
+
+    /// Displaces each pixel by the `map`'s red (x) and green (y) channels,
+    /// where 128 means no displacement, multiplied by `scale` pixels per level.
+    pub fn displace(&self, map: &Ppm, scale: f32) -> Result<Ppm, Error> {
+        self.check_same_size(map)?;
+        let mut ppm = self.clone();
+        for (x, y, offset) in map.iter_pixels() {
+            let dx = (offset.red as f32 - 128.0) * scale;
+            let dy = (offset.green as f32 - 128.0) * scale;
+            ppm.pixels[y * self.width + x] = self.sample_bilinear(x as f32 + dx, y as f32 + dy);
+        }
+        Ok(ppm)
+    }
+
+    pub fn distinct_row_count(&self) -> usize {
+        if self.width == 0 {
+            return self.height.min(1);
+        }
+        self.pixels.chunks(self.width).collect::<HashSet<_>>().len()
+    }
+
+    /// Applies a "curves" adjustment to luma. The curve is a monotone cubic
+    /// through `points`, which default to including (0, 0) and (255, 255).
+    pub fn tone_curve(&self, points: &[(u8, u8)]) -> Ppm {
+        let lut = tone_curve_lut(points);
+        let mut ppm = self.clone();
+        for pixel in ppm.pixels.iter_mut() {
+            let luma = pixel.luma();
+            let delta = lut[luma as usize] as i16 - luma as i16;
+            let shift = |value: u8| (value as i16 + delta).clamp(0, 255) as u8;
+            *pixel = Color::new(shift(pixel.red), shift(pixel.green), shift(pixel.blue));
+        }
+        ppm
+    }
+
+    /// Like [`Ppm::tone_curve`], but applies the curve to each channel.
+    pub fn tone_curve_per_channel(&self, points: &[(u8, u8)]) -> Ppm {
+        let lut = tone_curve_lut(points);
+        self.apply_luts(&lut, &lut, &lut)
+    }
+
+    /// FNV-1a hash over the dimensions, color depth and pixel bytes. The
+    /// integers are hashed as little-endian u64s so it's stable across platforms.
+    pub fn content_hash(&self) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        let mut feed = |byte: u8| {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        };
+        for value in [self.width, self.height, self.color_depth] {
+            (value as u64).to_le_bytes().into_iter().for_each(&mut feed);
+        }
+        for pixel in &self.pixels {
+            feed(pixel.red);
+            feed(pixel.green);
+            feed(pixel.blue);
+        }
+        hash
+    }
+
+    pub fn verify_hash(&self, expected: u64) -> bool {
+        self.content_hash() == expected
+    }
+
+    /// Pastes `src` aligned to `anchor`, clipping whatever falls outside.
+    /// Fails if no part of `src` lands on the image.
+    pub fn place(&mut self, src: &Ppm, anchor: Anchor) -> Result<(), Error> {
+        let right = self.width as isize - src.width as isize;
+        let bottom = self.height as isize - src.height as isize;
+        let (x, y) = match anchor {
+            Anchor::Center => (right / 2, bottom / 2),
+            Anchor::TopLeft => (0, 0),
+            Anchor::TopRight => (right, 0),
+            Anchor::BottomLeft => (0, bottom),
+            Anchor::BottomRight => (right, bottom),
+        };
+        let overlaps = |offset: isize, len: usize, bound: usize| {
+            offset.max(0) < (offset + len as isize).min(bound as isize)
+        };
+        if !overlaps(x, src.width, self.width) || !overlaps(y, src.height, self.height) {
+            return Err("Source doesn't overlap the image!".into());
+        }
+        for sy in 0..src.height {
+            for sx in 0..src.width {
+                self.put_pixel(
+                    x + sx as isize,
+                    y + sy as isize,
+                    &src.pixels[sy * src.width + sx],
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Variance of the luma Laplacian; higher means sharper.
+    pub fn focus_score(&self) -> f64 {
+        if self.width < 3 || self.height < 3 {
+            return 0.0;
+        }
+        let luma = self.luma_plane();
+        let at = |x: usize, y: usize| luma[y * self.width + x] as f64;
+        let mut responses = Vec::with_capacity((self.width - 2) * (self.height - 2));
+        for y in 1..self.height - 1 {
+            for x in 1..self.width - 1 {
+                responses.push(
+                    at(x - 1, y) + at(x + 1, y) + at(x, y - 1) + at(x, y + 1) - 4.0 * at(x, y),
+                );
+            }
+        }
+        let mean = responses.iter().sum::<f64>() / responses.len() as f64;
+        responses.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / responses.len() as f64
+    }
+
+    /// Builds an image from an array shaped `(height, width, 3)`.
+    #[cfg(feature = "ndarray")]
+    pub fn from_ndarray(arr: ndarray::ArrayView3<u8>) -> Result<Ppm, Error> {
+        let (height, width, channels) = arr.dim();
+        if channels != 3 {
+            return Err(format!("Expected 3 channels, got {}!", channels).into());
+        }
+        let mut ppm = Ppm::new(width, height);
+        for ((y, x), pixel) in ndarray::indices((height, width))
+            .into_iter()
+            .zip(ppm.pixels.iter_mut())
+        {
+            *pixel = Color::new(arr[(y, x, 0)], arr[(y, x, 1)], arr[(y, x, 2)]);
+        }
+        Ok(ppm)
+    }
+
+    /// Returns the pixels as an array shaped `(height, width, 3)`.
+    #[cfg(feature = "ndarray")]
+    pub fn to_ndarray(&self) -> ndarray::Array3<u8> {
+        ndarray::Array3::from_shape_fn((self.height, self.width, 3), |(y, x, c)| {
+            let pixel = &self.pixels[y * self.width + x];
+            [pixel.red, pixel.green, pixel.blue][c]
+        })
+    }
+
+    /// Adds a `bg` bar above or below the image holding `text`. The bar is
+    /// one glyph row tall per line of text, plus a 2 pixel margin.
+    pub fn add_caption(&self, text: &str, position: CaptionPosition, bg: Color, fg: Color) -> Ppm {
+        const MARGIN: usize = 2;
+        let lines = text.split('\n').count();
+        let bar = lines * (GLYPH_HEIGHT + 1) - 1 + 2 * MARGIN;
+        let mut ppm = Ppm::new(self.width, self.height + bar);
+        ppm.color_depth = self.color_depth;
+        let (image_y, bar_y) = match position {
+            CaptionPosition::Top => (bar, 0),
+            CaptionPosition::Bottom => (0, self.height),
+        };
+        ppm.paste(self, 0, image_y);
+        ppm.fill_rect(0, bar_y, ppm.width, bar, bg);
+        ppm.draw_text(text, MARGIN, bar_y + MARGIN, fg);
+        ppm
+    }
+
+    /// Area-averaged `grid x grid` luma thumbnail, row-major, for use as a
+    /// similarity fingerprint with [`Ppm::signature_distance`].
+    pub fn signature(&self, grid: usize) -> Vec<u8> {
+        let mut signature = Vec::with_capacity(grid * grid);
+        if self.pixels.is_empty() {
+            signature.resize(grid * grid, 0);
+            return signature;
+        }
+        for row in 0..grid {
+            let y0 = (row * self.height / grid).min(self.height - 1);
+            let y1 = ((row + 1) * self.height / grid).max(y0 + 1);
+            for col in 0..grid {
+                let x0 = (col * self.width / grid).min(self.width - 1);
+                let x1 = ((col + 1) * self.width / grid).max(x0 + 1);
+                signature.push(self.average_color(x0, y0, x1 - x0, y1 - y0).luma());
+            }
+        }
+        signature
+    }
+
+    /// Euclidean distance between two signatures, or `None` if their lengths
+    /// differ (e.g. they came from different grid sizes).
+    pub fn signature_distance(a: &[u8], b: &[u8]) -> Option<f64> {
+        if a.len() != b.len() {
+            return None;
+        }
+        let sum = a
+            .iter()
+            .zip(b)
+            .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+            .sum::<f64>();
+        Some(sum.sqrt())
+    }
+
+    /// Multiplies each pixel by the mask's luma as alpha (255 = opaque).
+    pub fn premultiply_with_mask(&self, mask: &Ppm) -> Result<Ppm, Error> {
+        self.check_same_size(mask)?;
+        let mut ppm = self.clone();
+        for (pixel, alpha) in ppm.pixels.iter_mut().zip(&mask.pixels) {
+            let alpha = alpha.luma() as u16;
+            let scale = |value: u8| ((value as u16 * alpha + 127) / 255) as u8;
+            *pixel = Color::new(scale(pixel.red), scale(pixel.green), scale(pixel.blue));
+        }
+        Ok(ppm)
+    }
+
+    pub fn rotate_region(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        degrees: f32,
+        background: Color,
+    ) -> Result<(), Error> {
+        let region = self.crop(x, y, width, height)?.rotate(degrees, background);
+        self.paste(&region, x, y);
+        Ok(())
+    }
+
+    /// Inverts every channel value above `threshold`.
+    pub fn solarize(&self, threshold: u8) -> Ppm {
+        let mut lut = [0u8; 256];
+        for (i, value) in lut.iter_mut().enumerate() {
+            *value = if i > threshold as usize {
+                255 - i as u8
+            } else {
+                i as u8
+            };
+        }
+        self.apply_luts(&lut, &lut, &lut)
+    }
+
+    /// Density plot of normalized (r, g) chromaticity, with r along x and g
+    /// increasing upwards. Brightness is log-scaled by pixel count; black
+    /// pixels are treated as neutral.
+    pub fn plot_chromaticity(&self, size: usize) -> Ppm {
+        if size == 0 {
+            return Ppm::new(0, 0);
+        }
+        let mut counts = vec![0usize; size * size];
+        let scale = (size - 1) as f32;
+        for pixel in &self.pixels {
+            let sum = pixel.red as f32 + pixel.green as f32 + pixel.blue as f32;
+            let (r, g) = match sum {
+                0.0 => (1.0 / 3.0, 1.0 / 3.0),
+                _ => (pixel.red as f32 / sum, pixel.green as f32 / sum),
+            };
+            let x = (r * scale).round() as usize;
+            let y = ((1.0 - g) * scale).round() as usize;
+            counts[y * size + x] += 1;
+        }
+        let max = (*counts.iter().max().unwrap_or(&0) as f32).ln_1p();
+        let plane: Vec<f32> = counts
+            .iter()
+            .map(|&count| match max {
+                0.0 => 0.0,
+                _ => 255.0 * (count as f32).ln_1p() / max,
+            })
+            .collect();
+        Ppm::from_plane(size, size, &plane)
+    }
+
+    /// Scales by `target_dpi / current_dpi` with bilinear resampling. A
+    /// non-positive DPI on either side returns the image unchanged.
+    pub fn resample_to_dpi(&self, current_dpi: f32, target_dpi: f32) -> Ppm {
+        if current_dpi <= 0.0 || target_dpi <= 0.0 {
+            return self.clone();
+        }
+        let scale = target_dpi / current_dpi;
+        let width = (self.width as f32 * scale).round() as usize;
+        let height = (self.height as f32 * scale).round() as usize;
+        self.resize(width, height)
+    }
+
+    /// White where every channel lies within its inclusive range, black elsewhere.
+    pub fn threshold_rgb(&self, r_range: (u8, u8), g_range: (u8, u8), b_range: (u8, u8)) -> Ppm {
+        let within = |value: u8, (low, high): (u8, u8)| (low..=high).contains(&value);
+        let mut ppm = self.clone();
+        for pixel in ppm.pixels.iter_mut() {
+            let value = if within(pixel.red, r_range)
+                && within(pixel.green, g_range)
+                && within(pixel.blue, b_range)
+            {
+                255
+            } else {
+                0
+            };
+            *pixel = Color::new(value, value, value);
+        }
+        ppm
+    }
+
+    /// Multiplies `self` over `background`, so white is transparent and
+    /// black is opaque ink.
+    pub fn multiply_composite(&self, background: &Ppm) -> Result<Ppm, Error> {
+        self.check_same_size(background)?;
+        let mut ppm = background.clone();
+        for (pixel, ink) in ppm.pixels.iter_mut().zip(&self.pixels) {
+            let multiply = |a: u8, b: u8| ((a as u16 * b as u16 + 127) / 255) as u8;
+            *pixel = Color::new(
+                multiply(pixel.red, ink.red),
+                multiply(pixel.green, ink.green),
+                multiply(pixel.blue, ink.blue),
+            );
+        }
+        Ok(ppm)
+    }
+
+    /// Whether every pixel is within `tolerance` of the first one.
+    pub fn is_uniform(&self, tolerance: u8) -> bool {
+        match self.pixels.first() {
+            Some(first) => self
+                .pixels
+                .iter()
+                .all(|pixel| pixel.is_close(first, tolerance)),
+            None => true,
+        }
+    }
+
+    /// Shows `self` before and `other` after a white divider at `split_ratio`.
+    /// A `vertical` divider splits left/right, otherwise top/bottom.
+    pub fn split_compare(
+        &self,
+        other: &Ppm,
+        split_ratio: f32,
+        vertical: bool,
+    ) -> Result<Ppm, Error> {
+        self.check_same_size(other)?;
+        let extent = if vertical { self.width } else { self.height };
+        let split = (split_ratio.clamp(0.0, 1.0) * extent as f32).round() as usize;
+        let mut ppm = self.clone();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let position = if vertical { x } else { y };
+                if position == split {
+                    ppm.pixels[y * self.width + x] = Color::new(255, 255, 255);
+                } else if position > split {
+                    ppm.pixels[y * self.width + x] = other.pixels[y * self.width + x].clone();
+                }
+            }
+        }
+        Ok(ppm)
+    }
+
+    /// Replaces each pixel with the average color of the most common luma
+    /// bin (out of `levels`) in its window. Windows are clamped at the edges.
+    pub fn oil_paint(&self, radius: usize, levels: usize) -> Ppm {
+        let levels = levels.max(1);
+        let radius = radius as isize;
+        let mut ppm = self.clone();
+        let mut counts = vec![0usize; levels];
+        let mut sums = vec![[0u32; 3]; levels];
+        for y in 0..self.height as isize {
+            for x in 0..self.width as isize {
+                counts.fill(0);
+                sums.fill([0; 3]);
+                for dy in -radius..=radius {
+                    for dx in -radius..=radius {
+                        let pixel = self.clamped_pixel(x + dx, y + dy);
+                        let bin = pixel.luma() as usize * levels / 256;
+                        counts[bin] += 1;
+                        sums[bin][0] += pixel.red as u32;
+                        sums[bin][1] += pixel.green as u32;
+                        sums[bin][2] += pixel.blue as u32;
+                    }
+                }
+                let (bin, &count) = counts
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|&(bin, count)| (count, std::cmp::Reverse(bin)))
+                    .unwrap();
+                let count = count as u32;
+                let average = |sum: u32| ((sum + count / 2) / count) as u8;
+                ppm.pixels[y as usize * self.width + x as usize] = Color::new(
+                    average(sums[bin][0]),
+                    average(sums[bin][1]),
+                    average(sums[bin][2]),
+                );
+            }
+        }
+        ppm
+    }
+
+    /// Grades the image through `lut` with trilinear interpolation.
+    pub fn apply_3d_lut(&self, lut: &CubeLut) -> Ppm {
+        let mut ppm = self.clone();
+        for pixel in ppm.pixels.iter_mut() {
+            *pixel = lut.lookup(pixel);
+        }
+        ppm
+    }
+
+    /// Boundary of the largest 4-connected non-background region, traced
+    /// clockwise with Moore-neighbor tracing from its top-left pixel. The
+    /// path is closed: its last pixel neighbors the first.
+    pub fn trace_outline(&self, background: Color, tolerance: u8) -> Vec<(usize, usize)> {
+        const NEIGHBORS: [(isize, isize); 8] = [
+            (-1, 0),
+            (-1, -1),
+            (0, -1),
+            (1, -1),
+            (1, 0),
+            (1, 1),
+            (0, 1),
+            (-1, 1),
+        ];
+        let Some((labels, label)) = self.largest_region(&background, tolerance) else {
+            return Vec::new();
+        };
+        let inside = |x: isize, y: isize| {
+            x >= 0
+                && y >= 0
+                && (x as usize) < self.width
+                && (y as usize) < self.height
+                && labels[y as usize * self.width + x as usize] == label
+        };
+        let start = labels.iter().position(|&l| l == label).unwrap();
+        let start = ((start % self.width) as isize, (start / self.width) as isize);
+        let mut path = vec![(start.0 as usize, start.1 as usize)];
+        // Entered from the west, since nothing in the region lies before `start`.
+        let Some(first_direction) =
+            (0..8).find(|&d| inside(start.0 + NEIGHBORS[d].0, start.1 + NEIGHBORS[d].1))
+        else {
+            return path;
+        };
+        let (mut current, mut direction) = (start, first_direction);
+        loop {
+            current = (
+                current.0 + NEIGHBORS[direction].0,
+                current.1 + NEIGHBORS[direction].1,
+            );
+            // Resume the clockwise scan just past the neighbor we came from.
+            let from = (direction + 4) % 8;
+            direction = (1..=8)
+                .map(|i| (from + i) % 8)
+                .find(|&d| inside(current.0 + NEIGHBORS[d].0, current.1 + NEIGHBORS[d].1))
+                .unwrap();
+            if current == start && direction == first_direction {
+                return path;
+            }
+            path.push((current.0 as usize, current.1 as usize));
+        }
+    }
+
+    /// Averages samples from each pixel towards (`cx`, `cy`) covering
+    /// `amount` of the distance (zoom), or along the arc around it covering
+    /// `amount` degrees (spin).
+    pub fn radial_blur(&self, cx: usize, cy: usize, amount: f32, spin: bool) -> Ppm {
+        if amount == 0.0 || self.pixels.is_empty() {
+            return self.clone();
+        }
+        let (cx, cy) = (cx as f32, cy as f32);
+        let mut ppm = self.clone();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let (dx, dy) = (x as f32 - cx, y as f32 - cy);
+                let radius = dx.hypot(dy);
+                let streak = if spin {
+                    radius * amount.to_radians().abs()
+                } else {
+                    radius * amount.abs()
+                };
+                let samples = (streak.ceil() as usize + 1).clamp(1, 64);
+                let mut sum = [0.0f32; 3];
+                for i in 0..samples {
+                    let t = i as f32 / samples as f32;
+                    let (sx, sy) = if spin {
+                        let (sin, cos) = (amount * t).to_radians().sin_cos();
+                        (cx + dx * cos - dy * sin, cy + dx * sin + dy * cos)
+                    } else {
+                        let scale = 1.0 - amount * t;
+                        (cx + dx * scale, cy + dy * scale)
+                    };
+                    let sample = self.sample_bilinear(sx, sy);
+                    sum[0] += sample.red as f32;
+                    sum[1] += sample.green as f32;
+                    sum[2] += sample.blue as f32;
+                }
+                let average = |sum: f32| (sum / samples as f32).round() as u8;
+                ppm.pixels[y * self.width + x] =
+                    Color::new(average(sum[0]), average(sum[1]), average(sum[2]));
+            }
+        }
+        ppm
+    }
+
+    /// Remaps each channel so its distribution follows `reference`'s.
+    pub fn match_histogram(&self, reference: &Ppm) -> Ppm {
+        if self.pixels.is_empty() || reference.pixels.is_empty() {
+            return self.clone();
+        }
+        let cdf = |ppm: &Ppm, channel: fn(&Color) -> u8| {
+            let mut histogram = [0usize; 256];
+            for pixel in &ppm.pixels {
+                histogram[channel(pixel) as usize] += 1;
+            }
+            let mut cdf = [0.0f64; 256];
+            let mut total = 0;
+            for (value, count) in cdf.iter_mut().zip(histogram) {
+                total += count;
+                *value = total as f64 / ppm.pixels.len() as f64;
+            }
+            cdf
+        };
+        let lut = |channel: fn(&Color) -> u8| {
+            let (source, target) = (cdf(self, channel), cdf(reference, channel));
+            let mut lut = [0u8; 256];
+            let mut j = 0;
+            for (value, &p) in lut.iter_mut().zip(&source) {
+                while j < 255 && target[j] < p - 1e-9 {
+                    j += 1;
+                }
+                *value = j as u8;
+            }
+            lut
+        };
+        self.apply_luts(&lut(|c| c.red), &lut(|c| c.green), &lut(|c| c.blue))
+    }
+
+    /// Maps `data` (row-major) through `map` after normalizing its finite
+    /// values to 0..1. Non-finite values, including NaN, become black.
+    pub fn from_scalar_field(
+        data: &[f32],
+        width: usize,
+        height: usize,
+        map: Colormap,
+    ) -> Result<Ppm, Error> {
+        let len = width.checked_mul(height).ok_or(INVALID_FORMAT)?;
+        if data.len() != len {
+            return Err(format!(
+                "Expected {} values for a {}x{} field, got {}!",
+                len,
+                width,
+                height,
+                data.len()
+            )
+            .into());
+        }
+        let finite = data.iter().filter(|value| value.is_finite());
+        let min = finite.clone().cloned().fold(f32::INFINITY, f32::min);
+        let max = finite.cloned().fold(f32::NEG_INFINITY, f32::max);
+        let mut ppm = Ppm::new(width, height);
+        for (pixel, &value) in ppm.pixels.iter_mut().zip(data) {
+            if value.is_finite() {
+                let t = if max > min {
+                    (value - min) / (max - min)
+                } else {
+                    0.0
+                };
+                *pixel = map.color(t);
+            }
+        }
+        Ok(ppm)
+    }
+
+    /// Contrast limited adaptive histogram equalization of luma over a
+    /// `tiles x tiles` grid. `clip_limit` caps each histogram bin at that
+    /// multiple of the mean bin count, and the excess is spread evenly.
+    pub fn clahe(&self, tiles: usize, clip_limit: f32) -> Ppm {
+        if self.pixels.is_empty() {
+            return self.clone();
+        }
+        let tiles_x = tiles.clamp(1, self.width);
+        let tiles_y = tiles.clamp(1, self.height);
+        let luma: Vec<u8> = self.pixels.iter().map(Color::luma).collect();
+        let mut luts = Vec::with_capacity(tiles_x * tiles_y);
+        for ty in 0..tiles_y {
+            let (y0, y1) = (ty * self.height / tiles_y, (ty + 1) * self.height / tiles_y);
+            for tx in 0..tiles_x {
+                let (x0, x1) = (tx * self.width / tiles_x, (tx + 1) * self.width / tiles_x);
+                let mut histogram = [0.0f32; 256];
+                for y in y0..y1 {
+                    for &value in &luma[y * self.width + x0..y * self.width + x1] {
+                        histogram[value as usize] += 1.0;
+                    }
+                }
+                let total = ((x1 - x0) * (y1 - y0)) as f32;
+                let limit = (clip_limit * total / 256.0).max(1.0);
+                let mut excess = 0.0;
+                for count in histogram.iter_mut() {
+                    excess += (*count - limit).max(0.0);
+                    *count = count.min(limit);
+                }
+                let mut lut = [0u8; 256];
+                let mut cdf = 0.0;
+                for (value, count) in lut.iter_mut().zip(histogram) {
+                    cdf += count + excess / 256.0;
+                    *value = (255.0 * cdf / total).round().clamp(0.0, 255.0) as u8;
+                }
+                luts.push(lut);
+            }
+        }
+        // Blend the mappings of the four nearest tile centers.
+        let neighbors = |position: f32, size: usize, count: usize| {
+            let grid = ((position + 0.5) * count as f32 / size as f32 - 0.5)
+                .clamp(0.0, (count - 1) as f32);
+            let low = grid.floor() as usize;
+            (low, (low + 1).min(count - 1), grid - low as f32)
+        };
+        let mut ppm = self.clone();
+        for y in 0..self.height {
+            let (ty0, ty1, fy) = neighbors(y as f32, self.height, tiles_y);
+            for x in 0..self.width {
+                let (tx0, tx1, fx) = neighbors(x as f32, self.width, tiles_x);
+                let value = luma[y * self.width + x] as usize;
+                let map = |tx: usize, ty: usize| luts[ty * tiles_x + tx][value] as f32;
+                let top = map(tx0, ty0) * (1.0 - fx) + map(tx1, ty0) * fx;
+                let bottom = map(tx0, ty1) * (1.0 - fx) + map(tx1, ty1) * fx;
+                let mapped = (top * (1.0 - fy) + bottom * fy).round() as i16;
+                let delta = mapped - value as i16;
+                let pixel = &mut ppm.pixels[y * self.width + x];
+                let shift = |value: u8| (value as i16 + delta).clamp(0, 255) as u8;
+                *pixel = Color::new(shift(pixel.red), shift(pixel.green), shift(pixel.blue));
+            }
+        }
+        ppm
+    }
+
+    /// Blends `color` over the rectangle at opacity `alpha` (clamped to 0..1).
+    pub fn highlight_rect(
+        &mut self,
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+        color: Color,
+        alpha: f32,
+    ) {
+        let alpha = alpha.clamp(0.0, 1.0);
+        let blend = |under: u8, over: u8| {
+            (under as f32 + (over as f32 - under as f32) * alpha).round() as u8
+        };
+        for py in y..(y + height).min(self.height) {
+            for px in x..(x + width).min(self.width) {
+                let pixel = &mut self.pixels[py * self.width + px];
+                *pixel = Color::new(
+                    blend(pixel.red, color.red),
+                    blend(pixel.green, color.green),
+                    blend(pixel.blue, color.blue),
+                );
+            }
+        }
+    }
+
+    /// Width to height reduced by their GCD. If either is zero the raw
+    /// dimensions are returned.
+    pub fn aspect_ratio(&self) -> (usize, usize) {
+        if self.width == 0 || self.height == 0 {
+            return (self.width, self.height);
+        }
+        let divisor = gcd(self.width, self.height);
+        (self.width / divisor, self.height / divisor)
+    }
+
+    /// Takes the wedge spanning 180/`segments` degrees clockwise from the
+    /// center's right, pairs it with its mirror image and repeats the pair
+    /// `segments` times around the center.
+    pub fn kaleidoscope(&self, segments: usize) -> Ppm {
+        if segments == 0 || self.pixels.is_empty() {
+            return self.clone();
+        }
+        let unit = std::f32::consts::TAU / segments as f32;
+        let cx = (self.width as f32 - 1.0) / 2.0;
+        let cy = (self.height as f32 - 1.0) / 2.0;
+        let mut ppm = self.clone();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let (dx, dy) = (x as f32 - cx, y as f32 - cy);
+                let mut angle = dy.atan2(dx).rem_euclid(unit);
+                if angle > unit / 2.0 {
+                    angle = unit - angle;
+                }
+                let radius = dx.hypot(dy);
+                let (sin, cos) = angle.sin_cos();
+                ppm.pixels[y * self.width + x] =
+                    self.sample_bilinear(cx + radius * cos, cy + radius * sin);
+            }
+        }
+        ppm
+    }
+
+    pub fn subtract_dark_frame(&self, dark: &Ppm) -> Result<Ppm, Error> {
+        self.check_same_size(dark)?;
+        let mut ppm = self.clone();
+        for (pixel, dark) in ppm.pixels.iter_mut().zip(&dark.pixels) {
+            *pixel = Color::new(
+                pixel.red.saturating_sub(dark.red),
+                pixel.green.saturating_sub(dark.green),
+                pixel.blue.saturating_sub(dark.blue),
+            );
+        }
+        Ok(ppm)
+    }
+
+    /// Divides out the unexposed film `base_color` before inverting, so the
+    /// orange mask of color negatives doesn't leave a blue cast.
+    pub fn invert_film_negative(&self, base_color: Color) -> Ppm {
+        let lut = |base: u8| {
+            let base = base.max(1) as f32;
+            let mut lut = [0u8; 256];
+            for (i, value) in lut.iter_mut().enumerate() {
+                *value = (255.0 * (1.0 - i as f32 / base)).round().clamp(0.0, 255.0) as u8;
+            }
+            lut
+        };
+        self.apply_luts(
+            &lut(base_color.red),
+            &lut(base_color.green),
+            &lut(base_color.blue),
+        )
+    }
+
+    /// Pads the shorter side evenly with `fill`; an odd remainder goes after
+    /// the image.
+    pub fn pad_to_square(&self, fill: Color) -> Ppm {
+        let side = self.width.max(self.height);
+        let mut ppm = Ppm::new(side, side);
+        ppm.color_depth = self.color_depth;
+        ppm.fill_rect(0, 0, side, side, fill);
+        ppm.paste(self, (side - self.width) / 2, (side - self.height) / 2);
+        ppm
+    }
+
+    /// Groups the `(col, row)` positions of pixel-identical tiles. Only groups
+    /// with more than one tile are returned, ordered by first occurrence.
+    /// Partial tiles at the edges are compared like any other.
+    pub fn find_duplicate_tiles(&self, tile_size: usize) -> Vec<Vec<(usize, usize)>> {
+        if tile_size == 0 {
+            return Vec::new();
+        }
+        let mut groups: Vec<Vec<(usize, usize)>> = Vec::new();
+        let mut index: HashMap<Vec<&[Color]>, usize> = HashMap::new();
+        for row in 0..self.height.div_ceil(tile_size) {
+            for col in 0..self.width.div_ceil(tile_size) {
+                let (x, y) = (col * tile_size, row * tile_size);
+                let width = tile_size.min(self.width - x);
+                let tile: Vec<&[Color]> = (y..(y + tile_size).min(self.height))
+                    .map(|ty| &self.pixels[ty * self.width + x..ty * self.width + x + width])
+                    .collect();
+                let group = *index.entry(tile).or_insert_with(|| {
+                    groups.push(Vec::new());
+                    groups.len() - 1
+                });
+                groups[group].push((col, row));
+            }
+        }
+        groups.retain(|group| group.len() > 1);
+        groups
+    }
+
+    /// Adds monochrome grain in `size`-pixel blocks, strongest in the midtones
+    /// and fading towards black and white. `intensity` 1 shifts a midtone by
+    /// up to 64 levels. The same `seed` always gives the same grain.
+    pub fn add_film_grain(&self, intensity: f32, size: usize, seed: u64) -> Ppm {
+        if intensity == 0.0 {
+            return self.clone();
+        }
+        let size = size.max(1);
+        let cols = self.width.div_ceil(size);
+        let mut ppm = self.clone();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let cell = ((y / size) * cols + x / size) as u64;
+                let random = splitmix64(seed ^ splitmix64(cell));
+                // Two uniforms summed give a softer, bell-shaped grain.
+                let noise =
+                    ((random >> 40) as f32 + (random & 0xFF_FFFF) as f32) / (1 << 24) as f32 - 1.0;
+                let pixel = &mut ppm.pixels[y * self.width + x];
+                let luma = pixel.luma() as f32 / 255.0;
+                let delta = noise * intensity * 64.0 * 4.0 * luma * (1.0 - luma);
+                let add = |value: u8| (value as f32 + delta).round().clamp(0.0, 255.0) as u8;
+                *pixel = Color::new(add(pixel.red), add(pixel.green), add(pixel.blue));
+            }
+        }
+        ppm
+    }
+
+    /// Draws marching-squares contour lines where luma crosses each of
+    /// `levels`, treating pixel centers as the sample grid.
+    pub fn draw_contours(&self, levels: &[u8], color: Color) -> Ppm {
+        let luma: Vec<f32> = self.pixels.iter().map(|p| p.luma() as f32).collect();
+        let mut ppm = self.clone();
+        for &level in levels {
+            let level = level as f32 + 0.5;
+            for y in 0..self.height.saturating_sub(1) {
+                for x in 0..self.width.saturating_sub(1) {
+                    let at = |x: usize, y: usize| luma[y * self.width + x];
+                    let corners = [
+                        ((x, y), at(x, y)),
+                        ((x + 1, y), at(x + 1, y)),
+                        ((x + 1, y + 1), at(x + 1, y + 1)),
+                        ((x, y + 1), at(x, y + 1)),
+                    ];
+                    // Crossing on each of the top, right, bottom and left edges.
+                    let crossings: Vec<Option<(f32, f32)>> = (0..4)
+                        .map(|i| {
+                            let (((ax, ay), a), ((bx, by), b)) = (corners[i], corners[(i + 1) % 4]);
+                            if (a >= level) == (b >= level) {
+                                return None;
+                            }
+                            let t = (level - a) / (b - a);
+                            Some((
+                                ax as f32 + t * (bx as f32 - ax as f32),
+                                ay as f32 + t * (by as f32 - ay as f32),
+                            ))
+                        })
+                        .collect();
+                    let pairs = match crossings.iter().flatten().count() {
+                        2 => {
+                            let mut points = crossings.iter().flatten();
+                            vec![(*points.next().unwrap(), *points.next().unwrap())]
+                        }
+                        4 => {
+                            let [top, right, bottom, left] =
+                                [0, 1, 2, 3].map(|i| crossings[i].unwrap());
+                            let center = corners.iter().map(|(_, v)| v).sum::<f32>() / 4.0;
+                            if (center >= level) == (corners[0].1 >= level) {
+                                vec![(top, right), (bottom, left)]
+                            } else {
+                                vec![(top, left), (right, bottom)]
+                            }
+                        }
+                        _ => Vec::new(),
+                    };
+                    for ((x0, y0), (x1, y1)) in pairs {
+                        let round = |v: f32| v.round() as isize;
+                        ppm.line((round(x0), round(y0)), (round(x1), round(y1)), &color);
+                    }
+                }
+            }
+        }
+        ppm
+    }
+
+    /// Divides each channel by a background estimate (two box blurs of
+    /// `radius`) and rescales by the background's mean, evening out smooth
+    /// lighting gradients.
+    pub fn flatten_illumination(&self, radius: usize) -> Ppm {
+        if self.pixels.is_empty() {
+            return self.clone();
+        }
+        let [red, green, blue] = self.channel_planes().map(|plane| {
+            let background = box_blur_plane(&plane, self.width, self.height, radius);
+            let background = box_blur_plane(&background, self.width, self.height, radius);
+            let mean = background.iter().sum::<f32>() / background.len() as f32;
+            plane
+                .iter()
+                .zip(&background)
+                .map(|(value, background)| value * mean / background.max(1.0))
+                .collect::<Vec<_>>()
+        });
+        self.with_channel_planes(&red, &green, &blue)
+    }
+
+    /// Shelf-packs `items` in order into rows at most `max_width` wide, with
+    /// `padding` around and between them, returning the atlas and each
+    /// item's `(x, y, width, height)`. An item wider than `max_width` gets a
+    /// row to itself and widens the atlas.
+    pub fn build_atlas(
+        items: &[Ppm],
+        max_width: usize,
+        padding: usize,
+        bg: Color,
+    ) -> (Ppm, Vec<(usize, usize, usize, usize)>) {
+        let mut rects = Vec::with_capacity(items.len());
+        let (mut x, mut y, mut row_height, mut width) = (padding, padding, 0, 0);
+        for item in items {
+            if x > padding && x + item.width + padding > max_width {
+                x = padding;
+                y += row_height + padding;
+                row_height = 0;
+            }
+            rects.push((x, y, item.width, item.height));
+            x += item.width + padding;
+            width = width.max(x);
+            row_height = row_height.max(item.height);
+        }
+        let height = if items.is_empty() {
+            0
+        } else {
+            y + row_height + padding
+        };
+        let mut atlas = Ppm::new(width, height);
+        atlas.fill_rect(0, 0, width, height, bg);
+        for (item, &(x, y, _, _)) in items.iter().zip(&rects) {
+            atlas.paste(item, x, y);
+        }
+        (atlas, rects)
+    }
+
+    /// Gaussian blur (sigma `radius / 2`) that only averages neighbors whose
+    /// channels all differ from the center by less than `threshold`, so
+    /// stronger edges survive. The window is clamped at the image edges.
+    pub fn surface_blur(&self, radius: usize, threshold: u8) -> Ppm {
+        let sigma = (radius as f32 / 2.0).max(0.5);
+        let radius = radius as isize;
+        let mut ppm = self.clone();
+        for y in 0..self.height as isize {
+            for x in 0..self.width as isize {
+                let center = self.clamped_pixel(x, y);
+                let mut sum = [0.0f32; 3];
+                let mut total = 0.0;
+                for dy in -radius..=radius {
+                    for dx in -radius..=radius {
+                        let pixel = self.clamped_pixel(x + dx, y + dy);
+                        if !pixel.is_close(center, threshold.saturating_sub(1)) {
+                            continue;
+                        }
+                        let weight = (-((dx * dx + dy * dy) as f32) / (2.0 * sigma * sigma)).exp();
+                        sum[0] += pixel.red as f32 * weight;
+                        sum[1] += pixel.green as f32 * weight;
+                        sum[2] += pixel.blue as f32 * weight;
+                        total += weight;
+                    }
+                }
+                // The center always qualifies, so `total` is positive.
+                let average = |sum: f32| (sum / total).round() as u8;
+                ppm.pixels[y as usize * self.width + x as usize] =
+                    Color::new(average(sum[0]), average(sum[1]), average(sum[2]));
+            }
+        }
+        ppm
+    }
+
+    /// Replaces each cell of a `grid_w x grid_h` grid with whichever of
+    /// `tiles` has the closest average color, resized to fit the cell.
+    pub fn photomosaic(&self, tiles: &[Ppm], grid_w: usize, grid_h: usize) -> Ppm {
+        if tiles.is_empty() || self.pixels.is_empty() {
+            return self.clone();
+        }
+        let averages: Vec<Color> = tiles
+            .iter()
+            .map(|tile| tile.average_color(0, 0, tile.width, tile.height))
+            .collect();
+        let cols = grid_w.clamp(1, self.width);
+        let rows = grid_h.clamp(1, self.height);
+        let mut ppm = self.clone();
+        for row in 0..rows {
+            let (y0, y1) = (row * self.height / rows, (row + 1) * self.height / rows);
+            for col in 0..cols {
+                let (x0, x1) = (col * self.width / cols, (col + 1) * self.width / cols);
+                let target = self.average_color(x0, y0, x1 - x0, y1 - y0);
+                let distance = |color: &Color| {
+                    let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+                    d(color.red, target.red)
+                        + d(color.green, target.green)
+                        + d(color.blue, target.blue)
+                };
+                let (best, _) = averages
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, average)| distance(average))
+                    .unwrap();
+                ppm.paste(&tiles[best].resize(x1 - x0, y1 - y0), x0, y0);
+            }
+        }
+        ppm
+    }
+
+    /// Hough transform of the Canny edge map (thresholds 20 and 40, sigma 1),
+    /// returning `(rho, theta)` for each accumulator peak with more than
+    /// `threshold` votes, strongest first. Lines satisfy
+    /// `x cos(theta) + y sin(theta) = rho`, with theta in whole degrees from
+    /// 0 to 179 and rho in pixels.
+    pub fn hough_lines(&self, threshold: u32) -> Vec<(f32, f32)> {
+        const THETAS: usize = 180;
+        const SUPPRESSION: isize = 3;
+        let edges = self.canny(20, 40, 1.0);
+        let diagonal = (self.width as f32).hypot(self.height as f32).ceil() as isize;
+        let rhos = (2 * diagonal + 1) as usize;
+        let trig: Vec<(f32, f32)> = (0..THETAS)
+            .map(|theta| (theta as f32).to_radians().sin_cos())
+            .collect();
+        let mut votes = vec![0u32; THETAS * rhos];
+        for (i, pixel) in edges.pixels.iter().enumerate() {
+            if pixel.red == 0 {
+                continue;
+            }
+            let (x, y) = ((i % self.width) as f32, (i / self.width) as f32);
+            for (theta, (sin, cos)) in trig.iter().enumerate() {
+                let rho = (x * cos + y * sin).round() as isize + diagonal;
+                votes[theta * rhos + rho as usize] += 1;
+            }
+        }
+        let mut lines = Vec::new();
+        for theta in 0..THETAS as isize {
+            for rho in 0..rhos as isize {
+                let count = votes[theta as usize * rhos + rho as usize];
+                if count <= threshold {
+                    continue;
+                }
+                // Keep only the first of equal maxima within the neighborhood.
+                let is_peak = (-SUPPRESSION..=SUPPRESSION).all(|dt| {
+                    (-SUPPRESSION..=SUPPRESSION).all(|dr| {
+                        let (t, r) = (theta + dt, rho + dr);
+                        if (dt, dr) == (0, 0)
+                            || t < 0
+                            || r < 0
+                            || t >= THETAS as isize
+                            || r >= rhos as isize
+                        {
+                            return true;
+                        }
+                        let other = votes[t as usize * rhos + r as usize];
+                        other < count || (other == count && (dt, dr) > (0, 0))
+                    })
+                });
+                if is_peak {
+                    lines.push((count, (rho - diagonal) as f32, theta as f32));
+                }
+            }
+        }
+        lines.sort_by_key(|&(count, _, _)| std::cmp::Reverse(count));
+        lines
+            .into_iter()
+            .map(|(_, rho, theta)| (rho, theta))
+            .collect()
+    }
+
+    /// Recolors each pixel by looking up its luma (0..1) in a gradient of
+    /// `(position, color)` stops sorted by position. Luma outside the stops
+    /// takes the nearest stop's color.
+    pub fn gradient_map(&self, stops: &[(f32, Color)]) -> Ppm {
+        let (Some(first), Some(last)) = (stops.first(), stops.last()) else {
+            return self.clone();
+        };
+        let mut lut = Vec::with_capacity(256);
+        for i in 0..256 {
+            let t = i as f32 / 255.0;
+            let color = if t <= first.0 {
+                first.1.clone()
+            } else if t >= last.0 {
+                last.1.clone()
+            } else {
+                let next = stops
+                    .iter()
+                    .position(|(position, _)| *position > t)
+                    .unwrap();
+                let ((p0, c0), (p1, c1)) = (&stops[next - 1], &stops[next]);
+                let f = (t - p0) / (p1 - p0);
+                let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * f).round() as u8;
+                Color::new(
+                    lerp(c0.red, c1.red),
+                    lerp(c0.green, c1.green),
+                    lerp(c0.blue, c1.blue),
+                )
+            };
+            lut.push(color);
+        }
+        let mut ppm = self.clone();
+        for pixel in ppm.pixels.iter_mut() {
+            *pixel = lut[pixel.luma() as usize].clone();
+        }
+        ppm
+    }
+
+    /// Mean Euclidean RGB distance from each pixel to `target`.
+    pub fn color_distance_to(&self, target: &Color) -> f64 {
+        if self.pixels.is_empty() {
+            return 0.0;
+        }
+        let total: f64 = self
+            .pixels
+            .iter()
+            .map(|pixel| {
+                let d = |a: u8, b: u8| (a as f64 - b as f64).powi(2);
+                (d(pixel.red, target.red)
+                    + d(pixel.green, target.green)
+                    + d(pixel.blue, target.blue))
+                .sqrt()
+            })
+            .sum();
+        total / self.pixels.len() as f64
+    }
+
+    /// Scales the last row of every `thickness` rows by `1 - darkness`.
+    pub fn scanlines(&self, darkness: f32, thickness: usize) -> Ppm {
+        let thickness = thickness.max(1);
+        let scale = (1.0 - darkness).clamp(0.0, 1.0);
+        let dim = |value: u8| (value as f32 * scale).round() as u8;
+        let mut ppm = self.clone();
+        for y in (thickness - 1..self.height).step_by(thickness) {
+            for pixel in &mut ppm.pixels[y * self.width..(y + 1) * self.width] {
+                *pixel = Color::new(dim(pixel.red), dim(pixel.green), dim(pixel.blue));
+            }
+        }
+        ppm
+    }
+
+    /// Like [`Ppm::scanlines`], plus an aperture-grille pattern: columns
+    /// cycle through red, green and blue, dimming the other two channels by
+    /// half of `darkness`.
+    pub fn scanlines_subpixel(&self, darkness: f32, thickness: usize) -> Ppm {
+        let scale = (1.0 - darkness / 2.0).clamp(0.0, 1.0);
+        let dim = |value: u8| (value as f32 * scale).round() as u8;
+        let mut ppm = self.scanlines(darkness, thickness);
+        for (i, pixel) in ppm.pixels.iter_mut().enumerate() {
+            let (red, green, blue) = (pixel.red, pixel.green, pixel.blue);
+            *pixel = match (i % self.width) % 3 {
+                0 => Color::new(red, dim(green), dim(blue)),
+                1 => Color::new(dim(red), green, dim(blue)),
+                _ => Color::new(dim(red), dim(green), blue),
+            };
+        }
+        ppm
+    }
+
+    /// Selects the 4-connected region of pixels within `tolerance` of the
+    /// pixel at (`x`, `y`). The mask is all false if that's out of bounds.
+    pub fn magic_wand(&self, x: usize, y: usize, tolerance: u8) -> Vec<bool> {
+        if x >= self.width || y >= self.height {
+            return vec![false; self.pixels.len()];
+        }
+        let seed = self.pixels[y * self.width + x].clone();
+        let labels = self.label_where(|pixel| pixel.is_close(&seed, tolerance));
+        let label = labels[y * self.width + x];
+        labels.into_iter().map(|l| l == label).collect()
+    }
+
+    /// Maps the quadrilateral `corners` (top-left, top-right, bottom-right,
+    /// bottom-left, in source pixels) onto an `out_w x out_h` image through a
+    /// homography. Output pixels that land outside the source are black.
+    pub fn perspective_warp(&self, corners: [(f32, f32); 4], out_w: usize, out_h: usize) -> Ppm {
+        let mut ppm = Ppm::new(out_w, out_h);
+        ppm.color_depth = self.color_depth;
+        let (right, bottom) = ((out_w.max(2) - 1) as f64, (out_h.max(2) - 1) as f64);
+        let targets = [(0.0, 0.0), (right, 0.0), (right, bottom), (0.0, bottom)];
+        let Some(h) = homography(targets, corners.map(|(x, y)| (x as f64, y as f64))) else {
+            return ppm;
+        };
+        for y in 0..out_h {
+            for x in 0..out_w {
+                let (x, y) = (x as f64, y as f64);
+                let w = h[6] * x + h[7] * y + 1.0;
+                let sx = ((h[0] * x + h[1] * y + h[2]) / w) as f32;
+                let sy = ((h[3] * x + h[4] * y + h[5]) / w) as f32;
+                if self.contains(sx, sy) {
+                    ppm.pixels[y as usize * out_w + x as usize] = self.sample_bilinear(sx, sy);
+                }
+            }
+        }
+        ppm
+    }
+
+    /// Ratio of the mean luma in the four corner regions to that of the
+    /// central region, each an eighth of the image's width and height.
+    /// 1 means no falloff and lower values mean darker corners; an image
+    /// with a black center reports 1.
+    pub fn measure_vignetting(&self) -> f64 {
+        if self.pixels.is_empty() {
+            return 1.0;
+        }
+        let (bw, bh) = ((self.width / 8).max(1), (self.height / 8).max(1));
+        let luma = self.luma_plane();
+        let mean = |x0: usize, y0: usize| {
+            let mut sum = 0.0f64;
+            for y in y0..y0 + bh {
+                for x in x0..x0 + bw {
+                    sum += luma[y * self.width + x] as f64;
+                }
+            }
+            sum / (bw * bh) as f64
+        };
+        let (right, bottom) = (self.width - bw, self.height - bh);
+        let center = mean((self.width - bw) / 2, (self.height - bh) / 2);
+        let corners = (mean(0, 0) + mean(right, 0) + mean(0, bottom) + mean(right, bottom)) / 4.0;
+        if center == 0.0 {
+            1.0
+        } else {
+            corners / center
+        }
+    }
+
+    pub fn pixel_max(&self, other: &Ppm) -> Result<Ppm, Error> {
+        self.combine(other, u8::max)
+    }
+
+    pub fn pixel_min(&self, other: &Ppm) -> Result<Ppm, Error> {
+        self.combine(other, u8::min)
+    }
+
+    fn combine(&self, other: &Ppm, op: impl Fn(u8, u8) -> u8) -> Result<Ppm, Error> {
+        self.check_same_size(other)?;
+        let mut ppm = self.clone();
+        for (pixel, other) in ppm.pixels.iter_mut().zip(&other.pixels) {
+            *pixel = Color::new(
+                op(pixel.red, other.red),
+                op(pixel.green, other.green),
+                op(pixel.blue, other.blue),
+            );
+        }
+        Ok(ppm)
+    }
+
+    /// Scales every channel by `target_mean` over the current mean luma,
+    /// saturating each channel at 255. A black image is returned unchanged.
+    pub fn auto_exposure(&self, target_mean: u8) -> Ppm {
+        let luma = self.luma_plane();
+        let mean = luma.iter().map(|&value| value as f64).sum::<f64>() / luma.len().max(1) as f64;
+        if mean == 0.0 {
+            return self.clone();
+        }
+        let gain = target_mean as f64 / mean;
+        let mut lut = [0u8; 256];
+        for (i, value) in lut.iter_mut().enumerate() {
+            *value = (i as f64 * gain).round().min(255.0) as u8;
+        }
+        self.apply_luts(&lut, &lut, &lut)
+    }
+
+    /// Renders the image `width` characters wide as Braille, one character
+    /// per 2x4 pixel block after resizing, raising a dot for every pixel
+    /// whose luma is at least `threshold`. Lines end with `\n`.
+    pub fn to_braille(&self, width: usize, threshold: u8) -> String {
+        // Dot bits for each (column, row) in a character cell.
+        const DOTS: [[u32; 4]; 2] = [[0x01, 0x02, 0x04, 0x40], [0x08, 0x10, 0x20, 0x80]];
+        if width == 0 || self.pixels.is_empty() {
+            return String::new();
+        }
+        let pixels_wide = width * 2;
+        let pixels_high = ((self.height * pixels_wide) as f32 / self.width as f32)
+            .round()
+            .max(1.0) as usize;
+        let image = self.resize(pixels_wide, pixels_high);
+        let mut braille = String::new();
+        for cell_y in 0..pixels_high.div_ceil(4) {
+            for cell_x in 0..width {
+                let mut bits = 0;
+                for (dx, column) in DOTS.iter().enumerate() {
+                    for (dy, bit) in column.iter().enumerate() {
+                        let (x, y) = (cell_x * 2 + dx, cell_y * 4 + dy);
+                        if y < pixels_high && image.pixels[y * pixels_wide + x].luma() >= threshold
+                        {
+                            bits |= bit;
+                        }
+                    }
+                }
+                braille.push(char::from_u32(0x2800 + bits).unwrap());
+            }
+            braille.push('\n');
+        }
+        braille
+    }
+
+    /// Unsharp mask (Gaussian sigma 1) applied only where the Sobel edge
+    /// magnitude, scaled as in [`Ppm::sobel`], exceeds `edge_threshold`.
+    pub fn edge_aware_sharpen(&self, amount: f32, edge_threshold: u8) -> Ppm {
+        let edges = self.energy_plane();
+        let [red, green, blue] = self.channel_planes().map(|plane| {
+            let blurred = gaussian_blur_plane(&plane, self.width, self.height, 1.0);
+            plane
+                .iter()
+                .zip(&blurred)
+                .zip(&edges)
+                .map(|((&value, &blurred), &edge)| {
+                    if edge / 4.0 > edge_threshold as f32 {
+                        value + amount * (value - blurred)
+                    } else {
+                        value
+                    }
+                })
+                .collect::<Vec<_>>()
+        });
+        self.with_channel_planes(&red, &green, &blue)
+    }
+
+    /// Reinhard tone mapping: each channel is linearized, scaled by
+    /// `exposure`, compressed with `c / (1 + c)` and re-encoded as sRGB.
+    pub fn tone_map_reinhard(&self, exposure: f32) -> Ppm {
+        let mut lut = [0u8; 256];
+        for (i, value) in lut.iter_mut().enumerate() {
+            let c = srgb_to_linear(i as u8) * exposure;
+            *value = linear_to_srgb(c / (1.0 + c));
+        }
+        self.apply_luts(&lut, &lut, &lut)
+    }
+
+    /// White where the images differ, black elsewhere. A pixel only differs
+    /// if nothing within `max_shift` pixels in the other image is within
+    /// `TOLERANT_DIFF_TOLERANCE` of it per channel, checked both ways, so
+    /// small rendering shifts don't register.
+    pub fn tolerant_diff(&self, other: &Ppm, max_shift: usize) -> Result<Ppm, Error> {
+        self.check_same_size(other)?;
+        let shift = max_shift as isize;
+        let matches_near = |a: &Ppm, b: &Ppm, x: isize, y: isize| {
+            let pixel = &a.pixels[y as usize * a.width + x as usize];
+            (-shift..=shift).any(|dy| {
+                (-shift..=shift).any(|dx| {
+                    let (nx, ny) = (x + dx, y + dy);
+                    nx >= 0
+                        && ny >= 0
+                        && (nx as usize) < b.width
+                        && (ny as usize) < b.height
+                        && b.pixels[ny as usize * b.width + nx as usize]
+                            .is_close(pixel, TOLERANT_DIFF_TOLERANCE)
+                })
+            })
+        };
+        let mut ppm = self.clone();
+        for y in 0..self.height as isize {
+            for x in 0..self.width as isize {
+                let unchanged = matches_near(self, other, x, y) && matches_near(other, self, x, y);
+                let value = if unchanged { 0 } else { 255 };
+                ppm.pixels[y as usize * self.width + x as usize] = Color::new(value, value, value);
+            }
+        }
+        Ok(ppm)
+    }
+}
+
+impl FromStr for Ppm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_str_ascii(s)
+    }
+}
+
+/// An image stored as palette indices.
+#[derive(Debug, Clone)]
+pub struct IndexedPpm {
+    width: usize,
+    height: usize,
+    palette: Vec<Color>,
+    indices: Vec<u8>,
+}
+
+impl IndexedPpm {
+    pub fn new(
+        width: usize,
+        height: usize,
+        palette: Vec<Color>,
+        indices: Vec<u8>,
+    ) -> Result<Self, Error> {
+        let len = width.checked_mul(height).ok_or(INVALID_FORMAT)?;
+        if indices.len() != len {
+            return Err(format!(
+                "Expected {} indices for a {}x{} image, got {}!",
+                len,
+                width,
+                height,
+                indices.len()
+            )
+            .into());
+        }
+        if let Some(&index) = indices.iter().find(|&&i| i as usize >= palette.len()) {
+            return Err(format!(
+                "Index {} is out of range for a palette of {} colors!",
+                index,
+                palette.len()
+            )
+            .into());
+        }
+        Ok(Self {
+            width,
+            height,
+            palette,
+            indices,
+        })
+    }
+
+    /// Builds a palette of the image's exact colors in order of first
+    /// appearance. Errors if there are more than 256.
+    pub fn from_ppm(ppm: &Ppm) -> Result<Self, Error> {
+        let mut palette = Vec::new();
+        let mut lookup = HashMap::new();
+        let mut indices = Vec::with_capacity(ppm.pixels.len());
+        for pixel in &ppm.pixels {
+            let index = match lookup.get(pixel) {
+                Some(&index) => index,
+                None => {
+                    if palette.len() == 256 {
+                        return Err("Image has more than 256 colors!".into());
+                    }
+                    palette.push(pixel.clone());
+                    lookup.insert(pixel.clone(), (palette.len() - 1) as u8);
+                    (palette.len() - 1) as u8
+                }
+            };
+            indices.push(index);
+        }
+        Self::new(ppm.width, ppm.height, palette, indices)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn palette(&self) -> &[Color] {
+        &self.palette
+    }
+
+    pub fn indices(&self) -> &[u8] {
+        &self.indices
+    }
+
+    pub fn to_ppm(&self) -> Ppm {
+        let mut ppm = Ppm::new(self.width, self.height);
+        for (pixel, &index) in ppm.pixels.iter_mut().zip(&self.indices) {
+            *pixel = self.palette[index as usize].clone();
+        }
+        ppm
+    }
+
+    /// Rotates palette entries `start..=end` forward by `steps`, so entry
+    /// `start` moves to `start + steps`. `end` is clamped to the palette.
+    pub fn cycle_palette(&mut self, start: usize, end: usize, steps: usize) {
+        let end = end.min(self.palette.len().saturating_sub(1));
+        if start >= end {
+            return;
+        }
+        let range = &mut self.palette[start..=end];
+        let len = range.len();
+        range.rotate_right(steps % len);
+    }
+}
+
+/// A 3D color lookup table of `size`³ output colors in 0..1, with red
+/// varying fastest as in the `.cube` format. Input colors are mapped from
+/// the `(min, max)` domain onto the table, clamped to its edges.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CubeLut {
+    size: usize,
+    table: Vec<[f32; 3]>,
+    domain: ([f32; 3], [f32; 3]),
+}
+
+impl CubeLut {
+    pub fn new(size: usize, table: Vec<[f32; 3]>) -> Result<Self, Error> {
+        if size < 2 {
+            return Err("LUT size must be at least 2!".into());
+        }
+        let entries = size
+            .checked_mul(size)
+            .and_then(|n| n.checked_mul(size))
+            .ok_or(INVALID_FORMAT)?;
+        if table.len() != entries {
+            return Err(format!("Expected {} LUT entries, got {}!", entries, table.len()).into());
+        }
+        Ok(Self {
+            size,
+            table,
+            domain: ([0.0; 3], [1.0; 3]),
+        })
+    }
+
+    /// The LUT that maps every color to itself. `size` is clamped to 2..=256,
+    /// as 8-bit input gains nothing from a finer grid.
+    pub fn identity(size: usize) -> Self {
+        let size = size.clamp(2, 256);
+        let scale = (size - 1) as f32;
+        let mut table = Vec::with_capacity(size * size * size);
+        for b in 0..size {
+            for g in 0..size {
+                for r in 0..size {
+                    table.push([r as f32 / scale, g as f32 / scale, b as f32 / scale]);
+                }
+            }
+        }
+        Self {
+            size,
+            table,
+            domain: ([0.0; 3], [1.0; 3]),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Parses the text `.cube` format, honoring `DOMAIN_MIN`/`DOMAIN_MAX`.
+    /// 1D LUTs aren't supported.
+    pub fn from_cube_str(s: &str) -> Result<Self, Error> {
+        let mut size = None;
+        let mut domain = ([0.0f32; 3], [1.0f32; 3]);
+        let mut table = Vec::new();
+        let triple = |values: &[&str]| -> Result<[f32; 3], Error> {
+            match values {
+                [r, g, b] => Ok([
+                    r.parse().map_err(|_| INVALID_FORMAT)?,
+                    g.parse().map_err(|_| INVALID_FORMAT)?,
+                    b.parse().map_err(|_| INVALID_FORMAT)?,
+                ]),
+                _ => Err(INVALID_FORMAT.into()),
+            }
+        };
+        for line in s.lines() {
+            let line = line.trim();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [] => {}
+                [first, ..] if first.starts_with('#') => {}
+                ["TITLE", ..] => {}
+                ["LUT_3D_SIZE", n] => size = Some(n.parse::<usize>().map_err(|_| INVALID_FORMAT)?),
+                ["LUT_1D_SIZE", ..] => return Err("1D LUTs aren't supported!".into()),
+                ["DOMAIN_MIN", values @ ..] => domain.0 = triple(values)?,
+                ["DOMAIN_MAX", values @ ..] => domain.1 = triple(values)?,
+                values => table.push(triple(values)?),
+            }
+        }
+        if (0..3).any(|c| domain.0[c] == domain.1[c]) {
+            return Err("DOMAIN_MIN must differ from DOMAIN_MAX!".into());
+        }
+        let mut lut = Self::new(size.ok_or("Missing LUT_3D_SIZE!")?, table)?;
+        lut.domain = domain;
+        Ok(lut)
+    }
+
+    fn lookup(&self, color: &Color) -> Color {
+        let scale = (self.size - 1) as f32;
+        let (min, max) = self.domain;
+        let split = |value: u8, c: usize| {
+            let input = (value as f32 / 255.0 - min[c]) / (max[c] - min[c]);
+            let position = input.clamp(0.0, 1.0) * scale;
+            let low = (position.floor() as usize).min(self.size - 2);
+            (low, position - low as f32)
+        };
+        let (r, fr) = split(color.red, 0);
+        let (g, fg) = split(color.green, 1);
+        let (b, fb) = split(color.blue, 2);
+        let mut out = [0.0f32; 3];
+        for (corner, weight) in [
+            ((0, 0, 0), (1.0 - fr) * (1.0 - fg) * (1.0 - fb)),
+            ((1, 0, 0), fr * (1.0 - fg) * (1.0 - fb)),
+            ((0, 1, 0), (1.0 - fr) * fg * (1.0 - fb)),
+            ((1, 1, 0), fr * fg * (1.0 - fb)),
+            ((0, 0, 1), (1.0 - fr) * (1.0 - fg) * fb),
+            ((1, 0, 1), fr * (1.0 - fg) * fb),
+            ((0, 1, 1), (1.0 - fr) * fg * fb),
+            ((1, 1, 1), fr * fg * fb),
+        ] {
+            let index = ((b + corner.2) * self.size + g + corner.1) * self.size + r + corner.0;
+            for (value, entry) in out.iter_mut().zip(self.table[index]) {
+                *value += weight * entry;
+            }
+        }
+        let channel = |value: f32| (value * 255.0).round().clamp(0.0, 255.0) as u8;
+        Color::new(channel(out[0]), channel(out[1]), channel(out[2]))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PnmFormat {
+    P5,
+    P6,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PixelOrder {
+    #[default]
+    RowMajor,
+    ColumnMajor,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorDistance {
+    #[default]
+    Rgb,
+    Perceptual,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarkerStyle {
+    Cross,
+    Plus,
+    Square,
+    Circle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Orientation {
+    Identity,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    FlipHorizontal,
+    FlipVertical,
+    Transpose,
+    Transverse,
+}
+
+impl Orientation {
+    pub const ALL: [Orientation; 8] = [
+        Orientation::Identity,
+        Orientation::Rotate90,
+        Orientation::Rotate180,
+        Orientation::Rotate270,
+        Orientation::FlipHorizontal,
+        Orientation::FlipVertical,
+        Orientation::Transpose,
+        Orientation::Transverse,
+    ];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Anchor {
+    Center,
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptionPosition {
+    Top,
+    Bottom,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Colormap {
+    #[default]
+    Grayscale,
+    /// Blue through green to red.
+    Heat,
+    Viridis,
+}
+
+impl Colormap {
+    fn color(&self, t: f32) -> Color {
+        const VIRIDIS: [(f32, f32, f32); 5] = [
+            (68.0, 1.0, 84.0),
+            (59.0, 82.0, 139.0),
+            (33.0, 145.0, 140.0),
+            (94.0, 201.0, 98.0),
+            (253.0, 231.0, 37.0),
+        ];
+        let t = t.clamp(0.0, 1.0);
+        match self {
+            Colormap::Grayscale => {
+                let value = (255.0 * t).round() as u8;
+                Color::new(value, value, value)
+            }
+            Colormap::Heat => heat_color(t),
+            Colormap::Viridis => {
+                let position = t * (VIRIDIS.len() - 1) as f32;
+                let i = (position as usize).min(VIRIDIS.len() - 2);
+                let f = position - i as f32;
+                let (a, b) = (VIRIDIS[i], VIRIDIS[i + 1]);
+                let lerp = |a: f32, b: f32| (a + (b - a) * f).round() as u8;
+                Color::new(lerp(a.0, b.0), lerp(a.1, b.1), lerp(a.2, b.2))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Error {
+    message: String,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(err: &str) -> Self {
+        Self {
+            message: err.to_string(),
+        }
+    }
+}
+
+impl From<String> for Error {
+    fn from(err: String) -> Self {
+        Self { message: err }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self {
+            message: err.to_string(),
+        }
+    }
+}
+
+struct Header {
+    width: usize,
+    height: usize,
+    color_depth: usize,
+    offset: usize,
+}
+
+fn parse_header(bytes: &[u8]) -> Result<Header, Error> {
+    let mut size = (None, None);
+    let mut color_depth = None;
+    let mut i = 0;
+    let mut next = 0;
+    while let Some(pos) = bytes[i..].iter().position(|b| 0xA.eq(b)) {
+        let chunk = &bytes[i..i + pos];
+        i += pos + 1;
+        if chunk.starts_with(&[0x23]) {
+            continue;
+        }
+        match next {
+            0 => {
+                if ![0x50, 0x36].eq(chunk) {
+                    return Err(INVALID_SIGNATURE.into());
+                }
+                next += 1;
+            }
+            1 => {
+                let dimensions = String::from_utf8_lossy(chunk);
+                let (width, height) = dimensions.split_once(0x20 as char).ok_or(UNEXPECTED_EOF)?;
+                let width = width.parse::<usize>().map_err(|_| INVALID_FORMAT)?;
+                let height = height.parse::<usize>().map_err(|_| INVALID_FORMAT)?;
+                size = (Some(width), Some(height));
+                next += 1;
+            }
+            2 => {
+                color_depth = Some(
+                    String::from_utf8_lossy(chunk)
+                        .parse::<usize>()
+                        .map_err(|_| INVALID_FORMAT)?,
+                );
+                break;
+            }
+            _ => unreachable!(),
+        }
+    }
+    if let ((Some(width), Some(height)), Some(color_depth)) = (size, color_depth) {
+        Ok(Header {
+            width,
+            height,
+            color_depth,
+            offset: i,
+        })
+    } else {
+        Err(INVALID_FORMAT.into())
+    }
+}
+
+fn box_blur_plane(plane: &[f32], width: usize, height: usize, radius: usize) -> Vec<f32> {
+    let mut integral = vec![0.0f64; (width + 1) * (height + 1)];
+    for y in 0..height {
+        let mut row = 0.0;
+        for x in 0..width {
+            row += plane[y * width + x] as f64;
+            integral[(y + 1) * (width + 1) + x + 1] = integral[y * (width + 1) + x + 1] + row;
+        }
+    }
+    let mut blurred = vec![0.0; plane.len()];
+    for y in 0..height {
+        let y0 = y.saturating_sub(radius);
+        let y1 = (y + radius + 1).min(height);
+        for x in 0..width {
+            let x0 = x.saturating_sub(radius);
+            let x1 = (x + radius + 1).min(width);
+            let sum = integral[y1 * (width + 1) + x1]
+                - integral[y0 * (width + 1) + x1]
+                - integral[y1 * (width + 1) + x0]
+                + integral[y0 * (width + 1) + x0];
+            blurred[y * width + x] = (sum / ((x1 - x0) * (y1 - y0)) as f64) as f32;
+        }
+    }
+    blurred
+}
+
+fn sobel_plane(plane: &[f32], width: usize, height: usize) -> (Vec<f32>, Vec<f32>) {
+    let at = |x: isize, y: isize| {
+        let x = x.clamp(0, width as isize - 1) as usize;
+        let y = y.clamp(0, height as isize - 1) as usize;
+        plane[y * width + x]
+    };
+    let mut gx = vec![0.0; plane.len()];
+    let mut gy = vec![0.0; plane.len()];
+    for y in 0..height as isize {
+        for x in 0..width as isize {
+            let i = y as usize * width + x as usize;
+            gx[i] = at(x + 1, y - 1) + 2.0 * at(x + 1, y) + at(x + 1, y + 1)
+                - at(x - 1, y - 1)
+                - 2.0 * at(x - 1, y)
+                - at(x - 1, y + 1);
+            gy[i] = at(x - 1, y + 1) + 2.0 * at(x, y + 1) + at(x + 1, y + 1)
+                - at(x - 1, y - 1)
+                - 2.0 * at(x, y - 1)
+                - at(x + 1, y - 1);
+        }
+    }
+    (gx, gy)
+}
+
+fn gaussian_blur_plane(plane: &[f32], width: usize, height: usize, sigma: f32) -> Vec<f32> {
+    let radius = (sigma * 3.0).ceil() as isize;
+    let kernel = (-radius..=radius)
+        .map(|i| (-(i * i) as f32 / (2.0 * sigma * sigma)).exp())
+        .collect::<Vec<_>>();
+    let total = kernel.iter().sum::<f32>();
+    let mut horizontal = vec![0.0; plane.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let mut sum = 0.0;
+            for (k, weight) in kernel.iter().enumerate() {
+                let sx = (x as isize + k as isize - radius).clamp(0, width as isize - 1) as usize;
+                sum += plane[y * width + sx] * weight;
+            }
+            horizontal[y * width + x] = sum / total;
+        }
+    }
+    let mut blurred = vec![0.0; plane.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let mut sum = 0.0;
+            for (k, weight) in kernel.iter().enumerate() {
+                let sy = (y as isize + k as isize - radius).clamp(0, height as isize - 1) as usize;
+                sum += horizontal[sy * width + x] * weight;
+            }
+            blurred[y * width + x] = sum / total;
+        }
+    }
+    blurred
+}
+
+fn heat_color(t: f32) -> Color {
+    let t = t.clamp(0.0, 1.0);
+    if t < 0.5 {
+        let t = t * 2.0;
+        Color::new(
+            0,
+            (255.0 * t).round() as u8,
+            (255.0 * (1.0 - t)).round() as u8,
+        )
+    } else {
+        let t = (t - 0.5) * 2.0;
+        Color::new(
+            (255.0 * t).round() as u8,
+            (255.0 * (1.0 - t)).round() as u8,
+            0,
+        )
+    }
+}
+
+fn fft(data: &mut [(f64, f64)], inverse: bool) {
+    let n = data.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+    let mut len = 2;
+    while len <= n {
+        let angle = std::f64::consts::TAU / len as f64 * if inverse { 1.0 } else { -1.0 };
+        let (sin, cos) = angle.sin_cos();
+        for start in (0..n).step_by(len) {
+            let mut w = (1.0, 0.0);
+            for k in 0..len / 2 {
+                let a = data[start + k];
+                let b = data[start + k + len / 2];
+                let t = (b.0 * w.0 - b.1 * w.1, b.0 * w.1 + b.1 * w.0);
+                data[start + k] = (a.0 + t.0, a.1 + t.1);
+                data[start + k + len / 2] = (a.0 - t.0, a.1 - t.1);
+                w = (w.0 * cos - w.1 * sin, w.0 * sin + w.1 * cos);
+            }
+        }
+        len <<= 1;
+    }
+    if inverse {
+        for value in data.iter_mut() {
+            *value = (value.0 / n as f64, value.1 / n as f64);
+        }
+    }
+}
+
+fn fft_2d(data: &mut [(f64, f64)], width: usize, height: usize, inverse: bool) {
+    for row in data.chunks_mut(width) {
+        fft(row, inverse);
+    }
+    let mut column = vec![(0.0, 0.0); height];
+    for x in 0..width {
+        for y in 0..height {
+            column[y] = data[y * width + x];
+        }
+        fft(&mut column, inverse);
+        for y in 0..height {
+            data[y * width + x] = column[y];
+        }
+    }
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u8 {
+    let value = value.clamp(0.0, 1.0);
+    let value = if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+    (value * 255.0).round() as u8
+}
+
+fn neighbor_median(values: &mut [u8], own: u8) -> u8 {
+    values.sort_unstable();
+    let middle = values.len() / 2;
+    let (low, high) = (values[middle - 1], values[middle]);
+    if own.abs_diff(low) <= own.abs_diff(high) {
+        low
+    } else {
+        high
+    }
+}
+
+fn median(values: &mut [u8]) -> u8 {
+    values.sort_unstable();
+    let middle = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[middle - 1] as u16 + values[middle] as u16).div_ceil(2) as u8
+    } else {
+        values[middle]
+    }
+}
+
+fn inscribed_size(width: f32, height: f32, sin: f32, cos: f32) -> (f32, f32) {
+    if width <= 0.0 || height <= 0.0 {
+        return (0.0, 0.0);
+    }
+    let (sin, cos) = (sin.abs(), cos.abs());
+    let (long, short) = if width >= height {
+        (width, height)
+    } else {
+        (height, width)
+    };
+    if short <= 2.0 * sin * cos * long || (sin - cos).abs() < 1e-6 {
+        let half = 0.5 * short;
+        if width >= height {
+            (half / sin, half / cos)
+        } else {
+            (half / cos, half / sin)
+        }
+    } else {
+        let cos_2a = cos * cos - sin * sin;
+        (
+            (width * cos - height * sin) / cos_2a,
+            (height * cos - width * sin) / cos_2a,
+        )
+    }
+}
+
+fn tone_curve_lut(points: &[(u8, u8)]) -> [u8; 256] {
+    let mut points = points.to_vec();
+    points.sort_by_key(|&(x, _)| x);
+    points.dedup_by_key(|&mut (x, _)| x);
+    if points.first().is_none_or(|&(x, _)| x != 0) {
+        points.insert(0, (0, 0));
+    }
+    if points.last().is_none_or(|&(x, _)| x != 255) {
+        points.push((255, 255));
+    }
+    let xs: Vec<f32> = points.iter().map(|&(x, _)| x as f32).collect();
+    let ys: Vec<f32> = points.iter().map(|&(_, y)| y as f32).collect();
+    let n = xs.len();
+    let slopes: Vec<f32> = (0..n - 1)
+        .map(|i| (ys[i + 1] - ys[i]) / (xs[i + 1] - xs[i]))
+        .collect();
+    // Fritsch-Carlson tangents keep the curve monotone between control points.
+    let mut tangents = vec![0.0f32; n];
+    tangents[0] = slopes[0];
+    tangents[n - 1] = slopes[n - 2];
+    for i in 1..n - 1 {
+        if slopes[i - 1] * slopes[i] > 0.0 {
+            tangents[i] = (slopes[i - 1] + slopes[i]) / 2.0;
+        }
+    }
+    for i in 0..n - 1 {
+        if slopes[i] == 0.0 {
+            tangents[i] = 0.0;
+            tangents[i + 1] = 0.0;
+            continue;
+        }
+        let a = tangents[i] / slopes[i];
+        let b = tangents[i + 1] / slopes[i];
+        let norm = a.hypot(b);
+        if norm > 3.0 {
+            tangents[i] = 3.0 * a / norm * slopes[i];
+            tangents[i + 1] = 3.0 * b / norm * slopes[i];
+        }
+    }
+    let mut lut = [0u8; 256];
+    let mut segment = 0;
+    for (x, value) in lut.iter_mut().enumerate() {
+        let x = x as f32;
+        while segment < n - 2 && x > xs[segment + 1] {
+            segment += 1;
+        }
+        let h = xs[segment + 1] - xs[segment];
+        let t = (x - xs[segment]) / h;
+        let (t2, t3) = (t * t, t * t * t);
+        let y = (2.0 * t3 - 3.0 * t2 + 1.0) * ys[segment]
+            + (t3 - 2.0 * t2 + t) * h * tangents[segment]
+            + (-2.0 * t3 + 3.0 * t2) * ys[segment + 1]
+            + (t3 - t2) * h * tangents[segment + 1];
+        *value = y.round().clamp(0.0, 255.0) as u8;
+    }
+    lut
+}
+
+fn gcd(mut a: usize, mut b: usize) -> usize {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+fn splitmix64(mut state: u64) -> u64 {
+    state = state.wrapping_add(0x9e3779b97f4a7c15);
+    state = (state ^ (state >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    state = (state ^ (state >> 27)).wrapping_mul(0x94d049bb133111eb);
+    state ^ (state >> 31)
+}
+
+/// Solves for the homography taking each `from` point to its `to` point,
+/// as the first eight entries of a 3x3 matrix whose last entry is 1.
+fn homography(from: [(f64, f64); 4], to: [(f64, f64); 4]) -> Option<[f64; 8]> {
+    let mut rows = [[0.0f64; 9]; 8];
+    for (i, ((x, y), (u, v))) in from.into_iter().zip(to).enumerate() {
+        rows[2 * i] = [x, y, 1.0, 0.0, 0.0, 0.0, -u * x, -u * y, u];
+        rows[2 * i + 1] = [0.0, 0.0, 0.0, x, y, 1.0, -v * x, -v * y, v];
+    }
+    for column in 0..8 {
+        let pivot =
+            (column..8).max_by(|&a, &b| rows[a][column].abs().total_cmp(&rows[b][column].abs()))?;
+        if rows[pivot][column].abs() < 1e-12 {
+            return None;
+        }
+        rows.swap(column, pivot);
+        let pivot_row = rows[column];
+        for (i, row) in rows.iter_mut().enumerate() {
+            if i != column {
+                let factor = row[column] / pivot_row[column];
+                for (value, pivot_value) in row.iter_mut().zip(pivot_row).skip(column) {
+                    *value -= factor * pivot_value;
+                }
+            }
+        }
+    }
+    Some(std::array::from_fn(|i| rows[i][8] / rows[i][i]))
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| {
+            group | (byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(group >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
         }
     }
+    encoded
 }